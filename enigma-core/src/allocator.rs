@@ -0,0 +1,110 @@
+// The allocator designs of the kernel. They only work on the heap bounds they are given and never touch
+// page tables, so the same code runs as the kernel heap and in the host tests. Mapping more memory when an
+// allocator runs out is left to the caller, see `FallbackAllocator::allocate_or_grow`.
+
+pub mod buddy;
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+pub mod stats;
+
+use core::{alloc::Layout, ptr, ptr::NonNull};
+
+/// An allocator for arbitrary layouts that `FixedSizeBlockAllocator` hands the requests to that don't
+/// fit any of its block sizes.
+pub trait FallbackAllocator {
+    /// Initializes the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must gurantee that the given heap bounds are valid
+    /// and that the heap is unused. This method must be called only once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Allocates memory for `layout`, `None` if there is no free region large enough.
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// Frees memory returned by `allocate` for the same `layout`.
+    ///
+    /// This function is unsafe because the caller must gurantee that `ptr` is not used anymore.
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout);
+
+    /// Adds the `by` bytes directly behind the current end of the heap.
+    ///
+    /// This function is unsafe because the caller must gurantee that this memory is mapped and unused.
+    unsafe fn extend(&mut self, by: usize);
+
+    /// Number of bytes the heap has to grow by so that an allocation of `layout` succeeds afterwards.
+    fn required_growth(&self, layout: &Layout) -> usize {
+        // Free memory at the end of the heap might be too small, so grow by the full size plus the
+        // worst case alignment padding
+        layout.size().saturating_add(layout.align())
+    }
+
+    /// Allocates memory for `layout`, growing the heap and retrying if the allocator is out of memory.
+    ///
+    /// `grow` is called with the number of bytes the heap has to grow by, maps at least that much memory
+    /// directly behind the heap and returns how much it mapped (the kernel passes `grow_heap`). Returns a
+    /// null pointer if the heap could not grow far enough.
+    fn allocate_or_grow(
+        &mut self,
+        layout: Layout,
+        grow: impl FnOnce(usize) -> Option<usize>,
+    ) -> *mut u8 {
+        if let Some(ptr) = self.allocate(layout) {
+            return ptr.as_ptr();
+        }
+        match grow(self.required_growth(&layout)) {
+            Some(added) => {
+                // The new pages directly follow the current end of the heap
+                unsafe { self.extend(added) };
+                self.allocate(layout).map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+            }
+            None => ptr::null_mut(),
+        }
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// `align` must be a power of two. Returns `None` if the aligned address doesn't fit in a `usize`.
+pub const fn align_up(addr: usize, align: usize) -> Option<usize> {
+    // let remainder = addr % alig;
+    // if remainder == 0 {
+    //     addr
+    // } else {
+    //     addr - remainder + alig
+    // }
+    match addr.checked_add(align - 1) {
+        Some(addr) => Some(addr & !(align - 1)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::align_up;
+
+    #[test]
+    fn align_up_rounds_to_next_multiple() {
+        assert_eq!(align_up(0, 8), Some(0));
+        assert_eq!(align_up(1, 8), Some(8));
+        assert_eq!(align_up(8, 8), Some(8));
+        assert_eq!(align_up(4097, 4096), Some(8192));
+        assert_eq!(align_up(0x4444_4444_0001, 16), Some(0x4444_4444_0010));
+    }
+
+    #[test]
+    fn align_up_with_align_one_is_identity() {
+        for addr in [0, 1, 7, 12345, usize::MAX] {
+            assert_eq!(align_up(addr, 1), Some(addr));
+        }
+    }
+
+    #[test]
+    fn align_up_past_the_address_space_is_none() {
+        assert_eq!(align_up(usize::MAX, 8), None);
+        assert_eq!(align_up(usize::MAX - 7, 8), Some(usize::MAX - 7));
+        let top = 1 << (usize::BITS - 1);
+        assert_eq!(align_up(1, top), Some(top));
+        assert_eq!(align_up(top + 1, top), None);
+    }
+}
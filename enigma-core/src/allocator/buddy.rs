@@ -0,0 +1,309 @@
+// Buddy Allocator:
+// The heap is managed in blocks whose size is a power of two (the "order" of a block). Every block of
+// order n + 1 can be split into two halves of order n, the two halves are called buddies. Because blocks
+// are always naturally aligned to their own size, the buddy of a block is found by flipping a single bit
+// of its address (`addr ^ size`), so no per-block headers are needed.
+
+// Allocation rounds the request up to the next power of two and takes a block of the smallest order that
+// has one free, splitting it until it has the requested order. The unused halves go to the free lists of
+// their orders. On free, the block is merged with its buddy as long as the buddy is free as well, so
+// unlike our linked list allocator the heap never stays fragmented after everything was freed. The price is
+// internal fragmentation: a 65 byte allocation uses a 128 byte block.
+
+use super::{align_up, stats::HeapUsage, FallbackAllocator};
+use core::{alloc::Layout, fmt, mem, ptr::NonNull};
+
+/// Size of the smallest block (order 0), large enough to hold a `ListNode`.
+const MIN_BLOCK_SIZE: usize = 16;
+/// Number of orders, the largest block is `MIN_BLOCK_SIZE << (ORDERS - 1)` (32 GiB).
+const ORDERS: usize = 32;
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct BuddyAllocator {
+    free_lists: [Option<&'static mut ListNode>; ORDERS], // ---> One list of free blocks per order
+    heap_end: usize,
+}
+
+impl BuddyAllocator {
+    /// Creates an empty BuddyAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        Self {
+            free_lists: [EMPTY; ORDERS],
+            heap_end: 0,
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must gurantee that the given heap bounds are valid
+    /// and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_end = heap_start + heap_size;
+        self.add_region(heap_start, self.heap_end);
+    }
+
+    /// Adds the memory directly behind the current end of the heap.
+    ///
+    /// This function is unsafe because the caller must gurantee that the `by` bytes after the heap
+    /// are mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        let start = self.heap_end;
+        self.heap_end += by;
+        self.add_region(start, self.heap_end);
+    }
+
+    /// Cuts `start..end` into the largest naturally aligned blocks that fit and frees them.
+    unsafe fn add_region(&mut self, start: usize, end: usize) {
+        let mut addr = match align_up(start, MIN_BLOCK_SIZE) {
+            Some(addr) => addr,
+            None => return,
+        };
+        while addr + MIN_BLOCK_SIZE <= end {
+            // largest order the address is aligned to, shrunk until the block fits
+            let alignment_order = addr.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros();
+            let mut order = (alignment_order as usize).min(ORDERS - 1);
+            while block_size(order) > end - addr {
+                order -= 1;
+            }
+            // Freeing merges the block with free buddies, e.g. with the old end of the heap on `extend`
+            self.free(addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates a block large enough for `layout`, splitting a larger block if needed.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let order = order_for(&layout)?;
+        // smallest order that has a free block
+        let found = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+        let block = self.pop(found);
+        // split it down, the upper halves become free blocks of the lower orders
+        for split in (order..found).rev() {
+            unsafe { self.push(block + block_size(split), split) };
+        }
+        NonNull::new(block as *mut u8)
+    }
+
+    /// Frees a block returned by `allocate` for the same `layout`.
+    ///
+    /// This function is unsafe because the caller must gurantee that `ptr` was allocated by this
+    /// allocator with `layout` and is not used anymore.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let order = order_for(&layout).expect("layout was never allocated");
+        self.free(ptr.as_ptr() as usize, order);
+    }
+
+    /// Puts the block back to its free list, merging it with its buddy as long as that is free too.
+    unsafe fn free(&mut self, mut addr: usize, mut order: usize) {
+        while order < ORDERS - 1 {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            // the merged block starts at the lower of the two buddies
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Adds the block at `addr` to the front of the free list of `order`.
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        assert!(mem::size_of::<ListNode>() <= block_size(order));
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: self.free_lists[order].take(),
+        });
+        self.free_lists[order] = Some(&mut *node_ptr);
+    }
+
+    /// Removes the first block of the free list of `order` and returns its address.
+    fn pop(&mut self, order: usize) -> usize {
+        let node = self.free_lists[order].take().expect("free list is empty");
+        self.free_lists[order] = node.next.take();
+        node as *mut ListNode as usize
+    }
+
+    /// Removes the block at `addr` from the free list of `order`, returns false if it is not free.
+    fn remove(&mut self, addr: usize, order: usize) -> bool {
+        // reference to current link, updated for each iteration
+        let mut current = &mut self.free_lists[order];
+        loop {
+            let is_match = match current.as_deref() {
+                Some(node) => node as *const ListNode as usize == addr,
+                None => return false,
+            };
+            if is_match {
+                let node = current.take().unwrap();
+                *current = node.next.take();
+                return true;
+            }
+            current = &mut current.as_mut().unwrap().next;
+        }
+    }
+}
+
+/// Size of the blocks of the given order.
+const fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+/// Smallest order whose blocks can hold `layout`, blocks are aligned to their size.
+fn order_for(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK_SIZE)
+        .checked_next_power_of_two()?;
+    let order = (size.trailing_zeros() - MIN_BLOCK_SIZE.trailing_zeros()) as usize;
+    (order < ORDERS).then_some(order)
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FallbackAllocator for BuddyAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        BuddyAllocator::init(self, heap_start, heap_size)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        BuddyAllocator::allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        BuddyAllocator::deallocate(self, ptr, layout)
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        BuddyAllocator::extend(self, by)
+    }
+
+    fn required_growth(&self, layout: &Layout) -> usize {
+        // The new memory must contain a naturally aligned block of the requested order
+        match order_for(layout) {
+            Some(order) => {
+                let size = block_size(order);
+                align_up(self.heap_end, size)
+                    .and_then(|start| start.checked_add(size))
+                    .map_or(usize::MAX, |end| end - self.heap_end)
+            }
+            None => usize::MAX,
+        }
+    }
+}
+
+impl HeapUsage for BuddyAllocator {
+    fn free_bytes(&self) -> usize {
+        (0..ORDERS)
+            .map(|order| {
+                let blocks = core::iter::successors(self.free_lists[order].as_deref(), |node| {
+                    node.next.as_deref()
+                });
+                blocks.count() * block_size(order)
+            })
+            .sum()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        (0..ORDERS)
+            .rev()
+            .find(|&order| self.free_lists[order].is_some())
+            .map_or(0, block_size)
+    }
+
+    fn write_free_lists(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        for order in 0..ORDERS {
+            let blocks = core::iter::successors(self.free_lists[order].as_deref(), |node| {
+                node.next.as_deref()
+            });
+            let count = blocks.count();
+            if count > 0 {
+                let size = block_size(order);
+                writeln!(out, "  order {:>2} ({} bytes): {} free", order, size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    #[repr(align(4096))]
+    struct TestRegion(#[allow(dead_code)] [u8; 4096]); // only reserves the memory
+
+    /// A BuddyAllocator over a fresh 4 KiB region that only has a single free block.
+    fn test_allocator() -> BuddyAllocator {
+        let region = Box::leak(Box::new(TestRegion([0; 4096])));
+        let mut allocator = BuddyAllocator::new();
+        unsafe { allocator.init(region as *mut TestRegion as usize, 4096) };
+        allocator
+    }
+
+    fn only_free_order(allocator: &BuddyAllocator) -> Option<usize> {
+        let mut orders = (0..ORDERS).filter(|&o| allocator.free_lists[o].is_some());
+        let order = orders.next();
+        assert_eq!(orders.next(), None);
+        order
+    }
+
+    // Splitting a 4 KiB block for 16 bytes leaves one free block on every lower order, freeing it
+    // merges everything back into the single 4 KiB block
+    #[test]
+    fn split_and_coalesce() {
+        let mut allocator = test_allocator();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = allocator.allocate(layout).unwrap();
+        assert!((0..8).all(|o| allocator.free_lists[o].is_some()));
+        unsafe { allocator.deallocate(ptr, layout) };
+        assert_eq!(only_free_order(&allocator), Some(8));
+    }
+
+    #[test]
+    fn blocks_are_aligned() {
+        let mut allocator = test_allocator();
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let aligned = Layout::from_size_align(24, 256).unwrap();
+        let first = allocator.allocate(small).unwrap();
+        let second = allocator.allocate(aligned).unwrap();
+        assert_eq!(second.as_ptr() as usize % 256, 0);
+        unsafe {
+            allocator.deallocate(first, small);
+            allocator.deallocate(second, aligned);
+        }
+        assert_eq!(only_free_order(&allocator), Some(8));
+    }
+
+    // After all memory was handed out allocations fail, until a block is freed again
+    #[test]
+    fn exhaustion_and_reuse() {
+        let mut allocator = test_allocator();
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let blocks = [(); 4].map(|_| allocator.allocate(layout).unwrap());
+        assert_eq!(allocator.allocate(layout), None);
+        unsafe { allocator.deallocate(blocks[2], layout) };
+        assert_eq!(allocator.allocate(layout), Some(blocks[2]));
+        for block in blocks {
+            unsafe { allocator.deallocate(block, layout) };
+        }
+        assert_eq!(only_free_order(&allocator), Some(8));
+    }
+
+    #[test]
+    fn too_large_layout_fails() {
+        let mut allocator = test_allocator();
+        let layout = Layout::from_size_align(8192, 8).unwrap();
+        assert_eq!(allocator.allocate(layout), None);
+        assert!(allocator.required_growth(&layout) >= 8192);
+    }
+}
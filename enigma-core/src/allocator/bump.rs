@@ -0,0 +1,388 @@
+// Bump Allocator:
+// The most simple allocator design is a bump allocator (also known as stack allocator). It allocates
+// memory linearly and only keeps track of the number of allocated bytes and the number of allocations.
+
+// Biggest  Advantage:  it’s very fast. Compared to other allocator designs (see below) that need to
+// actively look for a fitting memory block and perform various bookkeeping tasks on alloc and dealloc, a
+//  bump allocator can be optimized to just a few assembly instructions. This makes bump allocators useful
+//  for optimizing the allocation performance, for example when creating a virtual DOM library.
+
+// Arena:
+// As the global allocator a bump allocator can only reuse its memory once every allocation was freed,
+// which rarely happens in a kernel. An `Arena` makes the "free everything at once" explicit: it bumps
+// through a region carved from the heap and implements `core::alloc::Allocator`, so short-lived data
+// like the nodes of a parser can live in `Vec::new_in(&arena)` or `Box::new_in(value, &arena)` and is
+// freed by a single `reset`. A scope opened with `Arena::scope` remembers the current position and frees
+// everything allocated through it when it is dropped, the allocations made before stay alive.
+
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::Cell,
+    fmt,
+    ptr::{self, NonNull},
+};
+
+use super::{align_up, stats::HeapUsage, FallbackAllocator};
+
+/// Reserves `layout` at `next` if it fits below `end`, returns the start and the end of the allocation.
+fn bump(next: usize, end: usize, layout: &Layout) -> Option<(usize, usize)> {
+    let alloc_start = align_up(next, layout.align())?;
+    let alloc_end = alloc_start.checked_add(layout.size())?;
+    (alloc_end <= end).then_some((alloc_start, alloc_end))
+}
+
+pub struct BumpAllocator {
+    heap_start: usize,  // ---|
+    heap_end: usize,    // ---| To keep track of lower and upper bounds of heap memory regions
+    next: usize,        // ---> Should always point to first unsused byte of the heap
+    allocations: usize, // ---> counter for active allocations
+}
+
+impl BumpAllocator {
+    /// Creates a new empty bump allocator.
+    pub const fn new() -> Self {
+        Self {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Intializes the bump allocator with the given heap bounds
+    ///
+    /// This method is unsafe because the caller must ensure that the given
+    /// memory range is unused. Also, this method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FallbackAllocator for BumpAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        BumpAllocator::init(self, heap_start, heap_size)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        // None if out of memory
+        let (alloc_start, alloc_end) = bump(self.next, self.heap_end, &layout)?;
+        self.next = alloc_end;
+        self.allocations += 1;
+        NonNull::new(alloc_start as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
+}
+
+impl HeapUsage for BumpAllocator {
+    fn free_bytes(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    fn largest_free_block(&self) -> usize {
+        // only the memory behind `next` can be used, so it is never fragmented
+        self.heap_end - self.next
+    }
+
+    fn write_free_lists(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        // no free list, only the rest of the heap behind `next`
+        writeln!(
+            out,
+            "  {:#x}..{:#x}: {} bytes, {} live allocations",
+            self.next,
+            self.heap_end,
+            self.heap_end - self.next,
+            self.allocations
+        )
+    }
+}
+
+/// Alignment of the region an `Arena` carves from the heap.
+const ARENA_ALIGN: usize = 4096;
+
+/// A bump allocator over a region of the kernel heap whose allocations are freed all at once.
+pub struct Arena {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,       // ---> First unused byte of the region
+    high_water: Cell<usize>, // ---> Highest `next` ever reached
+    depth: Cell<usize>,      // ---> Number of open scopes, only the innermost one may allocate
+}
+
+impl Arena {
+    /// Carves a region of `capacity` bytes from the kernel heap, `None` if the heap has no room for it.
+    pub fn new(capacity: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(capacity.max(1), ARENA_ALIGN).ok()?;
+        let start = unsafe { alloc::alloc::alloc(layout) } as usize;
+        if start == 0 {
+            return None;
+        }
+        Some(Self {
+            start,
+            end: start + layout.size(),
+            next: Cell::new(start),
+            high_water: Cell::new(start),
+            depth: Cell::new(0),
+        })
+    }
+
+    /// Size of the region in bytes.
+    pub fn capacity(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Bytes currently allocated, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    /// The most bytes that were ever allocated at once since the arena was created.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water.get() - self.start
+    }
+
+    /// Frees everything allocated from the arena.
+    ///
+    /// Taking `&mut self` guarantees that no allocation or scope borrows the arena anymore.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+        self.depth.set(0); // a scope that was leaked with `mem::forget` is closed as well
+    }
+
+    /// Opens a nested scope, everything allocated through it is freed when it is dropped.
+    ///
+    /// While the scope is open only it can allocate, allocating from the arena itself or an outer scope
+    /// panics because that memory would be freed together with the scope.
+    pub fn scope(&self) -> ArenaScope<'_> {
+        let depth = self.depth.get() + 1;
+        self.depth.set(depth);
+        ArenaScope {
+            arena: self,
+            mark: self.next.get(),
+            depth,
+        }
+    }
+
+    fn allocate_at(&self, depth: usize, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        assert_eq!(
+            depth,
+            self.depth.get(),
+            "arena: allocation from an outer scope while an inner scope is open"
+        );
+        let (alloc_start, alloc_end) = bump(self.next.get(), self.end, &layout).ok_or(AllocError)?;
+        self.set_next(alloc_end);
+        let ptr = NonNull::new(alloc_start as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    /// Only the most recent allocation of the innermost scope is given back right away, everything else
+    /// stays allocated until the scope is closed or the arena is reset.
+    fn deallocate_at(&self, depth: usize, ptr: NonNull<u8>, layout: Layout) {
+        let start = ptr.as_ptr() as usize;
+        if depth == self.depth.get() && start + layout.size() == self.next.get() {
+            self.next.set(start);
+        }
+    }
+
+    /// Grows the most recent allocation in place, anything else is moved to a new allocation.
+    unsafe fn grow_at(
+        &self,
+        depth: usize,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let start = ptr.as_ptr() as usize;
+        let is_last = depth == self.depth.get() && start + old_layout.size() == self.next.get();
+        if is_last && start.is_multiple_of(new_layout.align()) {
+            if let Some(end) = start.checked_add(new_layout.size()).filter(|&end| end <= self.end) {
+                self.set_next(end);
+                return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+            }
+        }
+        let new = self.allocate_at(depth, new_layout)?;
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast::<u8>().as_ptr(), old_layout.size());
+        self.deallocate_at(depth, ptr, old_layout);
+        Ok(new)
+    }
+
+    fn set_next(&self, next: usize) {
+        self.next.set(next);
+        self.high_water.set(self.high_water.get().max(next));
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.capacity(), ARENA_ALIGN).unwrap();
+        unsafe { alloc::alloc::dealloc(self.start as *mut u8, layout) };
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_at(0, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocate_at(0, ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.grow_at(0, ptr, old_layout, new_layout)
+    }
+}
+
+/// A nested scope of an `Arena`, see `Arena::scope`.
+pub struct ArenaScope<'a> {
+    arena: &'a Arena,
+    mark: usize, // ---> Position of the arena when the scope was opened
+    depth: usize,
+}
+
+impl ArenaScope<'_> {
+    /// Opens a scope nested in this one.
+    pub fn scope(&self) -> ArenaScope<'_> {
+        self.arena.scope()
+    }
+
+    /// Bytes allocated through this scope.
+    pub fn used(&self) -> usize {
+        self.arena.next.get() - self.mark
+    }
+}
+
+impl Drop for ArenaScope<'_> {
+    fn drop(&mut self) {
+        assert_eq!(
+            self.depth,
+            self.arena.depth.get(),
+            "arena: scopes must be closed in the reverse order they were opened"
+        );
+        self.arena.depth.set(self.depth - 1);
+        self.arena.next.set(self.mark);
+    }
+}
+
+unsafe impl Allocator for ArenaScope<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.allocate_at(self.depth, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.arena.deallocate_at(self.depth, ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.arena.grow_at(self.depth, ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{boxed::Box, vec::Vec};
+
+    fn test_allocator(size: usize) -> BumpAllocator {
+        let region = Box::leak(std::vec![0u64; size / 8].into_boxed_slice());
+        let mut allocator = BumpAllocator::new();
+        unsafe { allocator.init(region.as_mut_ptr() as usize, size) };
+        allocator
+    }
+
+    // The heap is only reused once every allocation was freed
+    #[test]
+    fn reuses_heap_after_all_frees() {
+        let mut allocator = test_allocator(256);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let first = allocator.allocate(layout).unwrap();
+        let second = allocator.allocate(layout).unwrap();
+        assert_eq!(allocator.allocate(layout), None);
+        unsafe { allocator.deallocate(first, layout) };
+        assert_eq!(allocator.allocate(layout), None);
+        unsafe { allocator.deallocate(second, layout) };
+        assert_eq!(allocator.allocate(layout), Some(first));
+    }
+
+    #[test]
+    fn allocate_or_grow_extends_heap() {
+        let mut allocator = test_allocator(512);
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        allocator.allocate(layout).unwrap();
+        // the test region is 512 bytes, pretend the 256 bytes behind the first half were mapped
+        allocator.heap_end -= 256;
+        assert_eq!(allocator.allocate(layout), None);
+        let ptr = allocator.allocate_or_grow(layout, |min| {
+            assert!(min <= 256);
+            Some(256)
+        });
+        assert!(!ptr.is_null());
+        assert_eq!(allocator.free_bytes(), 512 - 400);
+        assert!(allocator.allocate_or_grow(layout, |_| None).is_null());
+    }
+
+    #[test]
+    fn arena_scopes() {
+        let arena = Arena::new(4096).unwrap();
+        let outer = Box::new_in(1u64, &arena);
+        let used = arena.used();
+        {
+            let scope = arena.scope();
+            let mut vec = Vec::new_in(&scope);
+            vec.extend(0..100u32);
+            {
+                let nested = scope.scope();
+                let value = Box::new_in([0u8; 1000], &nested);
+                assert_eq!(value.len(), 1000);
+            }
+            assert!(scope.used() >= 400);
+        }
+        assert_eq!(arena.used(), used);
+        assert!(arena.high_water_mark() >= used + 1400);
+        assert_eq!(*outer, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "inner scope is open")]
+    fn arena_rejects_outer_allocation_in_scope() {
+        let arena = Arena::new(4096).unwrap();
+        let _scope = arena.scope();
+        let _value = Box::new_in(1u64, &arena);
+    }
+
+    #[test]
+    #[should_panic(expected = "reverse order")]
+    fn arena_scopes_close_in_order() {
+        let arena = Arena::new(4096).unwrap();
+        let first = arena.scope();
+        let _second = arena.scope();
+        drop(first);
+    }
+}
@@ -0,0 +1,245 @@
+// The idea behind a fixed-size block allocator is the following: Instead of allocating exactly as much memory
+// as requested, we define a small number of block sizes and round up each allocation to the next
+// block size. For example, with block sizes of 16, 64, and 512 bytes, an allocation of 4 bytes would return
+// a 16-byte block, an allocation of 48 bytes a 64-byte block, and an allocation of 128 bytes a 512-byte block.
+
+// Like the linked list allocator, we keep track of the unused memory by creating a linked list in the
+// unused memory. However, instead of using a single list with different block sizes, we create a separate list
+// for each size class. Each list then only stores blocks of a single size.
+
+use core::{alloc::Layout, fmt, mem, ptr::NonNull};
+
+use super::{
+    linked_list::LinkedListAllocator,
+    stats::{BlockStats, HeapUsage},
+    FallbackAllocator,
+};
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be alwyas powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Serves small allocations from per-size free lists and hands everything else to the fallback `F`.
+pub struct FixedSizeBlockAllocator<F = LinkedListAllocator> {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: F, // Our linked list merges freed regions, so it can replace the crate's
+    block_allocations: [u64; BLOCK_SIZES.len()],
+    fallback_allocations: u64,
+    fallback_bytes_in_use: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator
+    pub const fn new() -> Self {
+        Self::with_fallback(LinkedListAllocator::new())
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: FallbackAllocator> FixedSizeBlockAllocator<F> {
+    /// Creates an empty FixedSizeBlockAllocator that uses the given (empty) fallback allocator.
+    pub const fn with_fallback(fallback_allocator: F) -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        Self {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator,
+            block_allocations: [0; BLOCK_SIZES.len()],
+            fallback_allocations: 0,
+            fallback_bytes_in_use: 0,
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds
+    ///
+    ///
+    /// This function is unsafe because the caller must gurantee that the given
+    /// heap bounds are valid and that the heap is unused. This methods must be
+    /// called only once
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Allocates memory for `layout` from its block list or the fallback allocator.
+    ///
+    /// If the fallback allocator is out of memory, the heap is grown with `grow` (see
+    /// `FallbackAllocator::allocate_or_grow`) and the allocation retried. Returns a null pointer if
+    /// that fails as well.
+    pub fn allocate(
+        &mut self,
+        layout: Layout,
+        grow: impl FnOnce(usize) -> Option<usize>,
+    ) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                self.block_allocations[index] += 1;
+                match self.list_heads[index].take() {
+                    Some(node) => {
+                        self.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // no block exists in list => allocate new block
+                        let block_size = BLOCK_SIZES[index];
+                        // only works if all block sizes are a power of 2
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        self.fallback_allocator.allocate_or_grow(layout, grow)
+                    }
+                }
+            }
+            None => {
+                let ptr = self.fallback_allocator.allocate_or_grow(layout, grow);
+                if !ptr.is_null() {
+                    self.fallback_allocations += 1;
+                    self.fallback_bytes_in_use += layout.size();
+                }
+                ptr
+            }
+        }
+    }
+
+    /// Frees memory returned by `allocate` for the same `layout`.
+    ///
+    /// This function is unsafe because the caller must gurantee that `ptr` is not used anymore.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr, layout);
+                self.fallback_bytes_in_use -= layout.size();
+            }
+        }
+    }
+}
+
+impl<F: HeapUsage> HeapUsage for FixedSizeBlockAllocator<F> {
+    fn free_bytes(&self) -> usize {
+        let free_blocks = self.free_block_counts();
+        let in_lists: usize = (0..BLOCK_SIZES.len())
+            .map(|index| free_blocks[index] * BLOCK_SIZES[index])
+            .sum();
+        in_lists + self.fallback_allocator.free_bytes()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        let largest_block = (0..BLOCK_SIZES.len())
+            .rev()
+            .find(|&index| self.list_heads[index].is_some())
+            .map_or(0, |index| BLOCK_SIZES[index]);
+        largest_block.max(self.fallback_allocator.largest_free_block())
+    }
+
+    fn block_stats(&self) -> Option<BlockStats> {
+        Some(BlockStats {
+            allocations: self.block_allocations,
+            free_blocks: self.free_block_counts(),
+            fallback_allocations: self.fallback_allocations,
+            fallback_bytes_in_use: self.fallback_bytes_in_use,
+        })
+    }
+
+    fn write_free_lists(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let free_blocks = self.free_block_counts();
+        for (index, size) in BLOCK_SIZES.iter().enumerate() {
+            writeln!(out, "  block {:>4}: {} free", size, free_blocks[index])?;
+        }
+        writeln!(out, "  fallback allocator:")?;
+        self.fallback_allocator.write_free_lists(out)
+    }
+}
+
+impl<F> FixedSizeBlockAllocator<F> {
+    /// Number of blocks in each free list.
+    fn free_block_counts(&self) -> [usize; BLOCK_SIZES.len()] {
+        let mut counts = [0; BLOCK_SIZES.len()];
+        for (count, head) in counts.iter_mut().zip(self.list_heads.iter()) {
+            *count = core::iter::successors(head.as_deref(), |node| node.next.as_deref()).count();
+        }
+        counts
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    #[repr(align(4096))]
+    struct TestRegion(#[allow(dead_code)] [u8; 4096]); // only reserves the memory
+
+    fn test_allocator() -> FixedSizeBlockAllocator {
+        let region = Box::leak(Box::new(TestRegion([0; 4096])));
+        let mut allocator = FixedSizeBlockAllocator::new();
+        unsafe { allocator.init(region as *mut TestRegion as usize, 4096) };
+        allocator
+    }
+
+    #[test]
+    fn list_index_rounds_up() {
+        let index = |size, align| list_index(&Layout::from_size_align(size, align).unwrap());
+        assert_eq!(index(1, 1), Some(0));
+        assert_eq!(index(9, 1), Some(1));
+        assert_eq!(index(8, 64), Some(3));
+        assert_eq!(index(2048, 8), Some(BLOCK_SIZES.len() - 1));
+        assert_eq!(index(2049, 8), None);
+    }
+
+    // A freed block goes to its list and is handed out again for the next request of its size
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut allocator = test_allocator();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let first = allocator.allocate(layout, |_| None);
+        assert_eq!(first as usize % 32, 0);
+        unsafe { allocator.deallocate(first, layout) };
+        assert_eq!(allocator.block_stats().unwrap().free_blocks[2], 1);
+        assert_eq!(allocator.allocate(layout, |_| None), first);
+        assert_eq!(allocator.block_stats().unwrap().allocations[2], 2);
+    }
+
+    #[test]
+    fn large_allocations_use_fallback() {
+        let mut allocator = test_allocator();
+        let layout = Layout::from_size_align(3000, 8).unwrap();
+        let ptr = allocator.allocate(layout, |_| None);
+        assert!(!ptr.is_null());
+        let stats = allocator.block_stats().unwrap();
+        assert_eq!(stats.fallback_allocations, 1);
+        assert_eq!(stats.fallback_bytes_in_use, 3000);
+        // the rest of the 4 KiB region can't hold another one
+        assert!(allocator.allocate(layout, |_| None).is_null());
+        unsafe { allocator.deallocate(ptr, layout) };
+        assert_eq!(allocator.block_stats().unwrap().fallback_bytes_in_use, 0);
+        assert_eq!(allocator.largest_free_block(), 4096);
+    }
+}
@@ -0,0 +1,444 @@
+// Linked List Allocator:
+// A common trick to keep track of an arbitrary number of free memory areas when implementing allocators
+// is to use these areas themselves as backing storage. This utilizes the fact that the regions are still
+// mapped to a virtual address and backed by a physical frame, but the stored information is not needed
+// anymore. By storing the information about the freed region in the region itself, we can keep track of
+// an unbounded number of freed regions without needing additional memory.
+// The most common implementation approach is to construct a single linked list in the freed memory, with
+// each node being a freed memory region
+
+// Each list node contains two fields: the size of the memory region and a pointer to the next unused memory
+// region. With this approach, we only need a pointer to the first unused region (called head) to keep track
+// of all unused regions, regardless of their number. The resulting data structure is often called a free list.
+
+// The list is kept sorted by address, so on deallocation the freed region is merged with the free regions
+// directly before and after it. Without merging, the heap would fall apart into ever smaller regions until
+// no allocation fits anymore.
+use super::{align_up, stats::HeapUsage, FallbackAllocator};
+use core::{alloc::Layout, fmt, mem, ptr::NonNull};
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        Self { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// Which free region an allocation is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    FirstFit, // ---> Lowest region that fits, fast and keeps the end of the heap free
+    NextFit,  // ---> First fitting region behind the previous allocation, spreads allocations out
+    BestFit,  // ---> Smallest region that fits, wastes the least memory but walks the whole list
+}
+
+pub struct LinkedListAllocator {
+    head: ListNode, // --> Points to first heap region, regions are sorted by address
+    policy: FitPolicy,
+    cursor: usize, // ---> Start of the free region the next next-fit search begins at, 0 = list start
+    heap_end: usize,
+}
+
+impl LinkedListAllocator {
+    /// Creates an empty LinkedListAllocator using first-fit
+    pub const fn new() -> Self {
+        Self::with_policy(FitPolicy::FirstFit)
+    }
+
+    /// Creates an empty LinkedListAllocator using the given policy
+    pub const fn with_policy(policy: FitPolicy) -> Self {
+        Self {
+            head: ListNode::new(0),
+            policy,
+            cursor: 0,
+            heap_end: 0,
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds
+    ///
+    /// This function is unsafe because the caller must gurantee that the given heap bounds are valid
+    /// and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_end = heap_start + heap_size;
+        self.add_free_region(heap_start, heap_size)
+    }
+
+    /// Adds the memory directly behind the current end of the heap.
+    ///
+    /// This function is unsafe because the caller must gurantee that the `by` bytes after the heap
+    /// are mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize) {
+        let start = self.heap_end;
+        self.heap_end += by;
+        self.add_free_region(start, by)
+    }
+
+    /// Changes the policy used for the following allocations
+    pub fn set_policy(&mut self, policy: FitPolicy) {
+        self.policy = policy;
+    }
+
+    /// Add the given memory region to the list, merging it with adjacent free regions.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        // ensure that the freed region is capable of holding ListNode
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), Some(addr));
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // find the last region in front of the new one (or the head)
+        let mut current = &mut self.head;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = current.next.take();
+        // merge with the following region, the cursor moves along to the start of the merged region
+        if let Some(next) = node.next.take() {
+            assert!(addr + size <= next.start_addr(), "freed region overlaps a free region");
+            if addr + size == next.start_addr() {
+                if self.cursor == next.start_addr() {
+                    self.cursor = addr;
+                }
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+        // merge with the preceding region, the head is no region and has size 0
+        if current.size > 0 {
+            assert!(current.end_addr() <= addr, "freed region overlaps a free region");
+            if current.end_addr() == addr {
+                if self.cursor == addr {
+                    self.cursor = current.start_addr();
+                }
+                current.size += node.size;
+                current.next = node.next.take();
+                return;
+            }
+        }
+
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        current.next = Some(&mut *node_ptr)
+    }
+
+    /// Looks for a free region with the given size and alignment according to the policy and removes
+    /// it from the list.
+    ///
+    /// Returns a tuple of the list node and the start address of the allocation.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let region_start = self.choose_region(size, align)?;
+
+        // reference to current list node, updated for each iteration
+        let mut current = &mut self.head;
+        while current.next.as_ref().unwrap().start_addr() != region_start {
+            current = current.next.as_mut().unwrap();
+        }
+        // region found -> remove node from list, the cursor can't stay on it
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+        if self.policy == FitPolicy::NextFit || self.cursor == region_start {
+            self.cursor = current.next.as_ref().map_or(0, |next| next.start_addr());
+        }
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        Some((region, alloc_start))
+    }
+
+    /// Returns the start address of the region the policy picks for the allocation.
+    fn choose_region(&self, size: usize, align: usize) -> Option<usize> {
+        let fits = |region: &&ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        match self.policy {
+            FitPolicy::FirstFit => self.regions().find(fits),
+            FitPolicy::NextFit => {
+                // from the cursor to the end of the list, then wrap around to its start
+                let cursor = self.cursor();
+                let wrapped = self.regions().take_while(|region| {
+                    cursor.is_none_or(|cursor| region.start_addr() != cursor.start_addr())
+                });
+                Self::regions_from(cursor).chain(wrapped).find(fits)
+            }
+            FitPolicy::BestFit => self.regions().filter(fits).min_by_key(|region| region.size),
+        }
+        .map(|region| region.start_addr())
+    }
+
+    /// The free region the next next-fit search begins at, `None` for the start of the list.
+    fn cursor(&self) -> Option<&ListNode> {
+        // the cursor is kept on the start of a region in the list, see `add_free_region` and `find_region`
+        (self.cursor != 0).then(|| unsafe { &*(self.cursor as *const ListNode) })
+    }
+
+    /// Iterates over the free regions in address order.
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        Self::regions_from(self.head.next.as_deref())
+    }
+
+    /// Iterates over the free regions in address order, starting at `first`.
+    fn regions_from(first: Option<&ListNode>) -> impl Iterator<Item = &ListNode> {
+        core::iter::successors(first, |node| node.next.as_deref())
+    }
+
+    /// Try to use the given region for an allocation with the given size and alignment.
+    ///
+    /// Returns the allocation start address on success
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align).ok_or(())?;
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            // Region is too small
+            return Err(());
+        }
+
+        // rest of region in front of or behind the allocation too small to hold a ListNode (required
+        // because the allocation splits the region in used and free parts)
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// Adjust the given layout so that the resulting allocated memory
+    /// region is also capable of storing a `ListNode`.
+    ///
+    /// Returns the adjusted size and alignment as a (size,align) tuple
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjustment alignment failed")
+            .pad_to_align();
+
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /// Allocates memory for `layout`, returning the unused parts of the chosen region to the list.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        // perform layout adjustments
+        let (size, align) = Self::size_align(layout);
+        let (region, alloc_start) = self.find_region(size, align)?;
+        let (region_start, region_end) = (region.start_addr(), region.end_addr());
+        let alloc_end = alloc_start.checked_add(size).expect("overflow");
+        unsafe {
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
+                if self.policy == FitPolicy::NextFit {
+                    self.cursor = alloc_end; // continue with the rest of the region
+                }
+            }
+        }
+        NonNull::new(alloc_start as *mut u8)
+    }
+
+    /// Frees memory returned by `allocate` for the same `layout`.
+    ///
+    /// This function is unsafe because the caller must gurantee that `ptr` is not used anymore.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // perform layout adjustments
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr.as_ptr() as usize, size)
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FallbackAllocator for LinkedListAllocator {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        LinkedListAllocator::init(self, heap_start, heap_size)
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        LinkedListAllocator::allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        LinkedListAllocator::deallocate(self, ptr, layout)
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        LinkedListAllocator::extend(self, by)
+    }
+
+    fn required_growth(&self, layout: &Layout) -> usize {
+        // The size after the adjustments, plus the worst case alignment padding
+        let (size, align) = Self::size_align(*layout);
+        size.saturating_add(align)
+    }
+}
+
+impl HeapUsage for LinkedListAllocator {
+    fn free_bytes(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.regions().map(|region| region.size).max().unwrap_or(0)
+    }
+
+    fn write_free_lists(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        for region in self.regions() {
+            writeln!(
+                out,
+                "  {:#x}..{:#x}: {} bytes",
+                region.start_addr(),
+                region.end_addr(),
+                region.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    #[repr(align(4096))]
+    struct TestRegion(#[allow(dead_code)] [u8; 4096]); // only reserves the memory
+
+    /// A LinkedListAllocator over a fresh 4 KiB region with the given policy.
+    fn test_allocator(policy: FitPolicy) -> LinkedListAllocator {
+        let region = Box::leak(Box::new(TestRegion([0; 4096])));
+        let mut allocator = LinkedListAllocator::with_policy(policy);
+        unsafe { allocator.init(region as *mut TestRegion as usize, 4096) };
+        allocator
+    }
+
+    /// Allocates 128 byte blocks and frees every other one, leaving holes of 128 bytes at the returned
+    /// addresses and a free tail behind the last block.
+    fn make_holes(allocator: &mut LinkedListAllocator, holes: usize) -> [Option<NonNull<u8>>; 8] {
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        // all blocks are allocated first, a block freed right away would just be handed out again
+        let blocks = [(); 16].map(|_| allocator.allocate(layout));
+        let mut freed = [None; 8];
+        for (hole, block) in freed.iter_mut().zip(blocks.iter().step_by(2)).take(holes) {
+            unsafe { allocator.deallocate(block.unwrap(), layout) };
+            *hole = *block;
+        }
+        for (i, hole) in freed.iter().enumerate().take(holes) {
+            assert!(hole.is_some() && !freed[..i].contains(hole), "holes are not distinct");
+        }
+        freed
+    }
+
+    // Freeing blocks in any order merges them back into a single region
+    #[test]
+    fn freed_regions_are_merged() {
+        let mut allocator = test_allocator(FitPolicy::FirstFit);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let blocks = [(); 4].map(|_| allocator.allocate(layout).unwrap());
+        for &index in &[1, 3, 0, 2] {
+            unsafe { allocator.deallocate(blocks[index], layout) };
+        }
+        assert_eq!(allocator.regions().count(), 1);
+        assert_eq!(allocator.regions().next().unwrap().size, 4096);
+    }
+
+    #[test]
+    fn first_fit_takes_lowest_region() {
+        let mut allocator = test_allocator(FitPolicy::FirstFit);
+        let holes = make_holes(&mut allocator, 2);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        assert_eq!(allocator.allocate(layout), holes[0]);
+    }
+
+    // A 128 byte request fits the holes exactly, best-fit must not cut it from the large tail region
+    #[test]
+    fn best_fit_takes_smallest_region() {
+        let mut allocator = test_allocator(FitPolicy::BestFit);
+        let holes = make_holes(&mut allocator, 2);
+        let tail = allocator.regions().last().unwrap().start_addr();
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let block = allocator.allocate(layout).unwrap();
+        assert!(holes.contains(&Some(block)));
+        assert_ne!(block.as_ptr() as usize, tail);
+    }
+
+    // After a block was taken from the first hole and freed again, first-fit would use that hole again
+    #[test]
+    fn next_fit_continues_after_last_allocation() {
+        let mut allocator = test_allocator(FitPolicy::FirstFit);
+        let holes = make_holes(&mut allocator, 3);
+        allocator.set_policy(FitPolicy::NextFit);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let first = allocator.allocate(layout).unwrap();
+        assert_eq!(Some(first), holes[0]);
+        unsafe { allocator.deallocate(first, layout) };
+        assert_eq!(allocator.allocate(layout), holes[1]);
+    }
+
+    // The cursor sits on the last region, which is too small, so the search has to wrap around
+    #[test]
+    fn next_fit_wraps_around() {
+        let mut allocator = test_allocator(FitPolicy::NextFit);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let blocks = [(); 32].map(|_| allocator.allocate(layout).unwrap());
+        for &index in &[0, 1, 30] {
+            unsafe { allocator.deallocate(blocks[index], layout) };
+        }
+        assert_eq!(allocator.allocate(layout), Some(blocks[0]));
+        assert_eq!(allocator.allocate(layout), Some(blocks[1]));
+        for &index in &[0, 1] {
+            unsafe { allocator.deallocate(blocks[index], layout) };
+        }
+        let large = Layout::from_size_align(256, 8).unwrap();
+        assert_eq!(allocator.allocate(large), Some(blocks[0]));
+    }
+
+    // Freeing the block in front of the cursor's region merges that region away, the cursor must follow
+    #[test]
+    fn next_fit_cursor_follows_merges() {
+        let mut allocator = test_allocator(FitPolicy::FirstFit);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let blocks = [(); 4].map(|_| allocator.allocate(layout).unwrap());
+        for &index in &[0, 2] {
+            unsafe { allocator.deallocate(blocks[index], layout) };
+        }
+        allocator.set_policy(FitPolicy::NextFit);
+        assert_eq!(allocator.allocate(layout), Some(blocks[0]));
+        unsafe { allocator.deallocate(blocks[1], layout) };
+        assert_eq!(allocator.allocate(layout), Some(blocks[1]));
+        assert_eq!(allocator.allocate(layout), Some(blocks[2]));
+    }
+
+    // Growing the heap adds the new memory to the free region at its old end
+    #[test]
+    fn extend_merges_with_last_region() {
+        let region = Box::leak(Box::new([TestRegion([0; 4096]), TestRegion([0; 4096])]));
+        let mut allocator = LinkedListAllocator::new();
+        unsafe { allocator.init(region.as_mut_ptr() as usize, 4096) };
+        let layout = Layout::from_size_align(6000, 8).unwrap();
+        assert_eq!(allocator.allocate(layout), None);
+        unsafe { allocator.extend(4096) };
+        assert_eq!(allocator.regions().count(), 1);
+        assert!(allocator.allocate(layout).is_some());
+    }
+}
@@ -0,0 +1,31 @@
+// The part of the heap statistics that only the allocator itself can answer: how much of its heap is free
+// and how fragmented it is. The kernel combines it with the allocation counters of its global allocator.
+
+use super::fixed_size_block::BLOCK_SIZES;
+use core::fmt;
+
+/// Lets an allocator report how much of its heap is free.
+pub trait HeapUsage {
+    /// Bytes that can still be allocated without growing the heap.
+    fn free_bytes(&self) -> usize;
+
+    /// Size of the largest allocation that succeeds without growing the heap.
+    fn largest_free_block(&self) -> usize;
+
+    /// Per block size counters, only the fixed-size block allocator has them.
+    fn block_stats(&self) -> Option<BlockStats> {
+        None
+    }
+
+    /// Writes the content of the free lists to `out`, one line per list, for the out-of-memory report.
+    fn write_free_lists(&self, out: &mut dyn fmt::Write) -> fmt::Result;
+}
+
+/// Counters of the fixed-size block allocator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockStats {
+    pub allocations: [u64; BLOCK_SIZES.len()], // ---> Allocations served per entry of `BLOCK_SIZES`
+    pub free_blocks: [usize; BLOCK_SIZES.len()], // ---> Blocks currently in each free list
+    pub fallback_allocations: u64,             // ---> Allocations too large for any block size
+    pub fallback_bytes_in_use: usize,
+}
@@ -0,0 +1,16 @@
+// Kernel logic that doesn't touch the hardware: the heap allocator designs, the VGA text writer and the
+// lookup in the embedded symbol table. The kernel only adds the parts that need the real machine (page
+// tables, locks with interrupts disabled, the VGA buffer at 0xb8000), so everything in here can be tested
+// on the host with the normal test harness, see `scripts/host-tests.sh`.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(allocator_api)]
+// The contract of every `unsafe fn` is spelled out as "This function is unsafe because ..." like in the
+// kernel, not under a `# Safety` heading
+#![allow(clippy::missing_safety_doc)]
+
+extern crate alloc;
+
+pub mod allocator;
+pub mod symbols;
+pub mod vga;
@@ -0,0 +1,103 @@
+// Symbol Table:
+// A backtrace of raw return addresses needs the kernel ELF and a debugger to be read. So after linking,
+// `scripts/embed-symbols.sh` writes the start, size and demangled name of every function into a section
+// reserved in the kernel image, and the kernel looks its return addresses up in there. The table is text,
+// one function per line and sorted by start address:
+//
+//     0000000000201a30 00000000000000b4 enigma::allocator::grow_heap
+//
+// It ends at the first NUL byte, the rest of the reserved section stays zeroed.
+
+use core::str;
+
+/// A function in the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub start: u64,
+    pub size: u64,
+    pub name: &'a str,
+}
+
+impl Symbol<'_> {
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    text: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// The table in `bytes`, up to the first NUL byte.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+        SymbolTable { text: &bytes[..len] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Every well-formed line of the table, malformed ones are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = Symbol<'a>> {
+        self.text.split(|&byte| byte == b'\n').filter_map(parse_line)
+    }
+
+    /// The function containing `address`.
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'a>> {
+        self.iter()
+            .take_while(|symbol| symbol.start <= address)
+            .filter(|symbol| symbol.contains(address))
+            .last()
+    }
+}
+
+fn parse_line(line: &[u8]) -> Option<Symbol<'_>> {
+    let mut fields = line.splitn(3, |&byte| byte == b' ');
+    let start = parse_hex(fields.next()?)?;
+    let size = parse_hex(fields.next()?)?;
+    let name = str::from_utf8(fields.next()?).ok()?;
+    Some(Symbol { start, size, name })
+}
+
+fn parse_hex(field: &[u8]) -> Option<u64> {
+    u64::from_str_radix(str::from_utf8(field).ok()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Symbol, SymbolTable};
+
+    const TABLE: &[u8] = b"0000000000201000 0000000000000040 enigma::init\n\
+        0000000000201040 0000000000000010 <T as enigma::testing::Testable>::run\n\
+        0000000000201080 0000000000000100 enigma::hlt_loop\n\0\0\0\0";
+
+    #[test]
+    fn finds_the_function_containing_an_address() {
+        let table = SymbolTable::new(TABLE);
+        assert_eq!(table.lookup(0x201000).unwrap().name, "enigma::init");
+        assert_eq!(table.lookup(0x20103f).unwrap().name, "enigma::init");
+        let run = table.lookup(0x201044).unwrap();
+        assert_eq!(run.name, "<T as enigma::testing::Testable>::run");
+        assert_eq!(0x201044 - run.start, 4);
+        assert_eq!(table.lookup(0x20117f).unwrap().name, "enigma::hlt_loop");
+    }
+
+    #[test]
+    fn addresses_between_and_outside_functions_have_no_symbol() {
+        let table = SymbolTable::new(TABLE);
+        assert_eq!(table.lookup(0x200fff), None);
+        assert_eq!(table.lookup(0x201050), None); // gap after `run`
+        assert_eq!(table.lookup(0x201180), None);
+    }
+
+    #[test]
+    fn table_ends_at_nul_and_skips_malformed_lines() {
+        let table = SymbolTable::new(b"zz 10 broken\n0000000000001000 10 ok\n\0ffff 10 after_end\n");
+        let symbols: Vec<Symbol> = table.iter().collect();
+        assert_eq!(symbols, [Symbol { start: 0x1000, size: 0x10, name: "ok" }]);
+        assert!(SymbolTable::new(&[0; 64]).is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+// VGA Text Mode:
+// The writer only sees a `Buffer` in memory, the kernel hands it the real VGA buffer at 0xb8000 (see
+// `vga_buffer::WRITER`), the host tests a buffer on the heap.
+
+use core::fmt;
+use volatile::Volatile; //To mark our read/write as volatile(means they have side effect and should not be optimized)
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)] // To expilcitly specify color no use c-like enum. Stores each enum as u8
+pub enum Color {
+    // VGA Colors
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)] // To get Same Memory layout as u8
+pub struct ColorCode(u8);
+
+impl ColorCode {
+    pub fn new(foreground: Color, background: Color) -> Self {
+        // Color Format according to VGA attribute
+        Self((background as u8) << 4 | (foreground as u8))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)] // To guarantee that field are laid exactly like C so field ordering remain correct so
+           // that it directly maps to VGA Buffer cell
+pub struct ScreenChar {
+    pub ascii_character: u8,
+    pub color_code: ColorCode,
+}
+
+// VGA Default Text Mode (80x25)
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+#[repr(transparent)] // To have same memory layout as single field
+pub struct Buffer {
+    // VGA Buffer (at 0xb8000)
+    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
+pub struct Writer {
+    col_position: usize, // Current Position in last row
+    color_code: ColorCode,
+    buffer: &'static mut Buffer,
+}
+
+impl Writer {
+    /// Creates a writer that starts at the beginning of the last row of `buffer`.
+    pub fn new(buffer: &'static mut Buffer, color_code: ColorCode) -> Self {
+        Self {
+            col_position: 0,
+            color_code,
+            buffer,
+        }
+    }
+
+    /// Reads the character at `row` and `col` back from the buffer.
+    pub fn screen_char(&self, row: usize, col: usize) -> ScreenChar {
+        self.buffer.chars[row][col].read()
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            match byte {
+                // VGA Supports Code Page 437 character set
+                // Printable ASCII Byte b/w ` ` to `~` or newline
+                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // Non Printable ASCII Range, we print `■`
+                _ => self.write_byte(0xfe),
+            }
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            byte => {
+                if self.col_position >= BUFFER_WIDTH {
+                    self.new_line();
+                }
+
+                let row = BUFFER_HEIGHT - 1; // Because writing on last line
+                let col = self.col_position;
+
+                let color_code = self.color_code;
+
+                // Compiler will not optimize this write
+                self.buffer.chars[row][col].write(ScreenChar {
+                    ascii_character: byte,
+                    color_code,
+                });
+
+                self.col_position += 1;
+            }
+        }
+    }
+
+    // write current character to line above it and topline gets deleted.
+    fn new_line(&mut self) {
+        for row in 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.buffer.chars[row][col].read();
+                self.buffer.chars[row - 1][col].write(character);
+            }
+        }
+        self.clear_row(BUFFER_HEIGHT - 1);
+        self.col_position = 0;
+    }
+
+    // Clear row by overwritting space character
+    fn clear_row(&mut self, row: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+
+        for col in 0..BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(blank);
+        }
+    }
+}
+
+// To Support write! and writeln! formatting macros
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use std::alloc::{alloc_zeroed, Layout};
+
+    /// A writer over a zeroed buffer on the heap instead of the VGA buffer.
+    fn test_writer() -> Writer {
+        let buffer = unsafe { &mut *(alloc_zeroed(Layout::new::<Buffer>()) as *mut Buffer) };
+        Writer::new(buffer, ColorCode::new(Color::Yellow, Color::Blue))
+    }
+
+    fn row_text(writer: &Writer, row: usize, len: usize) -> std::string::String {
+        (0..len)
+            .map(|col| char::from(writer.screen_char(row, col).ascii_character))
+            .collect()
+    }
+
+    #[test]
+    fn writes_to_last_row_with_color() {
+        let mut writer = test_writer();
+        writer.write_string("hello");
+        assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1, 5), "hello");
+        let color = ColorCode::new(Color::Yellow, Color::Blue);
+        assert_eq!(writer.screen_char(BUFFER_HEIGHT - 1, 0).color_code, color);
+        assert_eq!(color, ColorCode(0x1e));
+    }
+
+    #[test]
+    fn newline_scrolls_up() {
+        let mut writer = test_writer();
+        write!(writer, "first\nsecond").unwrap();
+        assert_eq!(row_text(&writer, BUFFER_HEIGHT - 2, 5), "first");
+        assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1, 6), "second");
+    }
+
+    #[test]
+    fn long_lines_wrap() {
+        let mut writer = test_writer();
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'a');
+        }
+        writer.write_byte(b'b');
+        assert_eq!(writer.screen_char(BUFFER_HEIGHT - 2, BUFFER_WIDTH - 1).ascii_character, b'a');
+        assert_eq!(writer.screen_char(BUFFER_HEIGHT - 1, 0).ascii_character, b'b');
+        assert_eq!(writer.screen_char(BUFFER_HEIGHT - 1, 1).ascii_character, b' ');
+    }
+
+    #[test]
+    fn non_printable_bytes_become_squares() {
+        let mut writer = test_writer();
+        writer.write_string("a\tü");
+        let row = BUFFER_HEIGHT - 1;
+        assert_eq!(writer.screen_char(row, 0).ascii_character, b'a');
+        // the tab and both UTF-8 bytes of `ü`
+        assert!((1..4).all(|col| writer.screen_char(row, col).ascii_character == 0xfe));
+    }
+
+    #[test]
+    fn top_row_is_dropped() {
+        let mut writer = test_writer();
+        for line in 0..=BUFFER_HEIGHT {
+            write!(writer, "\n{}", line % 10).unwrap();
+        }
+        // the last line written is at the bottom, line 0 scrolled out of the top
+        let digit = |row| writer.screen_char(row, 0).ascii_character;
+        assert_eq!(digit(BUFFER_HEIGHT - 1), b'0' + (BUFFER_HEIGHT % 10) as u8);
+        assert_eq!(digit(0), b'1');
+    }
+}
@@ -0,0 +1,236 @@
+// Randomized alloc/free sequences against a reference model: the model knows which allocations are live
+// and checks that every new allocation is aligned, lies inside the heap and doesn't overlap a live one.
+// Each allocation is filled with its own byte, which must still be there when it is freed, so an
+// allocator that writes its bookkeeping into live memory is caught as well.
+
+use enigma_core::allocator::{
+    buddy::BuddyAllocator,
+    bump::BumpAllocator,
+    fixed_size_block::FixedSizeBlockAllocator,
+    linked_list::{FitPolicy, LinkedListAllocator},
+    stats::HeapUsage,
+    FallbackAllocator,
+};
+use std::{alloc::Layout, collections::BTreeMap, ptr::NonNull};
+
+const HEAP_SIZE: usize = 64 * 1024;
+const STEPS: usize = 4000;
+const SEEDS: [u64; 4] = [1, 0x5eed, 0xdead_beef, 0x1234_5678_9abc];
+
+/// A fresh heap region of `HEAP_SIZE` bytes, returned as its start address.
+///
+/// It is aligned to its size, so the buddy allocator can merge it back into a single block.
+fn heap_region() -> usize {
+    let layout = Layout::from_size_align(HEAP_SIZE, HEAP_SIZE).unwrap();
+    let start = unsafe { std::alloc::alloc_zeroed(layout) };
+    assert!(!start.is_null());
+    start as usize
+}
+
+/// xorshift64*, deterministic so a failing seed can be replayed.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    fn layout(&mut self) -> Layout {
+        let size = match self.below(100) {
+            0..=69 => 1 + self.below(256),
+            70..=94 => 257 + self.below(1792),
+            _ => 2049 + self.below(6144),
+        };
+        let align = match self.below(100) {
+            0..=89 => 1 << self.below(4), // 1 to 8
+            90..=98 => 1 << (4 + self.below(3)), // 16 to 64
+            _ => 256,
+        };
+        Layout::from_size_align(size, align).unwrap()
+    }
+}
+
+/// A live allocation in the model.
+struct Live {
+    end: usize,
+    layout: Layout,
+    tag: u8,
+}
+
+/// The allocations the model expects to be live, by start address.
+struct Model {
+    heap_start: usize,
+    live: BTreeMap<usize, Live>,
+    next_tag: u8,
+}
+
+impl Model {
+    fn new(heap_start: usize) -> Self {
+        Self {
+            heap_start,
+            live: BTreeMap::new(),
+            next_tag: 0,
+        }
+    }
+
+    /// Checks a new allocation against the live ones and fills it with its tag.
+    fn allocated(&mut self, ptr: *mut u8, layout: Layout, heap_end: usize) {
+        let start = ptr as usize;
+        let end = start + layout.size();
+        assert_eq!(start % layout.align(), 0, "{:?} at {:#x} is misaligned", layout, start);
+        assert!(start >= self.heap_start && end <= heap_end, "{:#x} is outside the heap", start);
+        if let Some((_, before)) = self.live.range(..start).next_back() {
+            assert!(before.end <= start, "{:#x} overlaps the allocation in front", start);
+        }
+        if let Some((&after, _)) = self.live.range(start..).next() {
+            assert!(end <= after, "{:#x} overlaps the allocation behind", start);
+        }
+
+        self.next_tag = self.next_tag.wrapping_add(1);
+        unsafe { ptr.write_bytes(self.next_tag, layout.size()) };
+        let tag = self.next_tag;
+        self.live.insert(start, Live { end, layout, tag });
+    }
+
+    /// Removes a random live allocation after checking that its content is untouched.
+    fn free_random(&mut self, rng: &mut Rng) -> (*mut u8, Layout) {
+        let start = *self.live.keys().nth(rng.below(self.live.len())).unwrap();
+        self.remove(start)
+    }
+
+    fn remove(&mut self, start: usize) -> (*mut u8, Layout) {
+        let live = self.live.remove(&start).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(start as *const u8, live.layout.size()) };
+        assert!(
+            bytes.iter().all(|&byte| byte == live.tag),
+            "allocation at {:#x} was overwritten while it was live",
+            start
+        );
+        (start as *mut u8, live.layout)
+    }
+}
+
+/// Runs `STEPS` random allocations and frees for each seed, then frees everything that is left.
+///
+/// `new` creates the allocator over the given heap start, `finished` checks it once all is freed.
+fn run<H: HeapUsage>(
+    new: impl Fn(usize) -> H,
+    alloc: impl Fn(&mut H, Layout) -> *mut u8,
+    free: impl Fn(&mut H, *mut u8, Layout),
+    finished: impl Fn(&H),
+) {
+    for seed in SEEDS {
+        let heap_start = heap_region();
+        let heap_end = heap_start + HEAP_SIZE;
+        let mut heap = new(heap_start);
+        let mut model = Model::new(heap_start);
+        let mut rng = Rng(seed);
+
+        for _ in 0..STEPS {
+            if !model.live.is_empty() && rng.below(100) < 45 {
+                let (ptr, layout) = model.free_random(&mut rng);
+                free(&mut heap, ptr, layout);
+            } else {
+                let layout = rng.layout();
+                let ptr = alloc(&mut heap, layout);
+                if !ptr.is_null() {
+                    model.allocated(ptr, layout, heap_end);
+                }
+            }
+            assert!(heap.free_bytes() <= HEAP_SIZE);
+            assert!(heap.largest_free_block() <= heap.free_bytes());
+        }
+
+        while let Some(&start) = model.live.keys().next() {
+            let (ptr, layout) = model.remove(start);
+            free(&mut heap, ptr, layout);
+        }
+        finished(&heap);
+    }
+}
+
+fn run_fallback<A: FallbackAllocator + HeapUsage>(new: impl Fn() -> A, finished: impl Fn(&A)) {
+    run(
+        |start| {
+            let mut allocator = new();
+            unsafe { allocator.init(start, HEAP_SIZE) };
+            allocator
+        },
+        |allocator, layout| {
+            allocator
+                .allocate(layout)
+                .map_or(std::ptr::null_mut(), NonNull::as_ptr)
+        },
+        |allocator, ptr, layout| {
+            let ptr = NonNull::new(ptr).unwrap();
+            unsafe { allocator.deallocate(ptr, layout) }
+        },
+        finished,
+    );
+}
+
+/// Once everything is freed, the free regions must have merged back into the whole heap.
+fn fully_merged<H: HeapUsage>(heap: &H) {
+    assert_eq!(heap.free_bytes(), HEAP_SIZE);
+    assert_eq!(heap.largest_free_block(), HEAP_SIZE);
+}
+
+#[test]
+fn linked_list_first_fit() {
+    run_fallback(|| LinkedListAllocator::with_policy(FitPolicy::FirstFit), fully_merged);
+}
+
+#[test]
+fn linked_list_next_fit() {
+    run_fallback(|| LinkedListAllocator::with_policy(FitPolicy::NextFit), fully_merged);
+}
+
+#[test]
+fn linked_list_best_fit() {
+    run_fallback(|| LinkedListAllocator::with_policy(FitPolicy::BestFit), fully_merged);
+}
+
+#[test]
+fn buddy() {
+    run_fallback(BuddyAllocator::new, fully_merged);
+}
+
+#[test]
+fn bump() {
+    run_fallback(BumpAllocator::new, fully_merged);
+}
+
+fn run_fixed_size_block<F: FallbackAllocator + HeapUsage>(fallback: impl Fn() -> F) {
+    run(
+        |start| {
+            let mut allocator = FixedSizeBlockAllocator::with_fallback(fallback());
+            unsafe { allocator.init(start, HEAP_SIZE) };
+            allocator
+        },
+        |allocator, layout| allocator.allocate(layout, |_| None),
+        |allocator, ptr, layout| unsafe { allocator.deallocate(ptr, layout) },
+        |allocator| {
+            // freed blocks stay in their lists, only the large allocations go back to the fallback
+            let stats = allocator.block_stats().unwrap();
+            assert_eq!(stats.fallback_bytes_in_use, 0);
+            assert!(allocator.free_bytes() <= HEAP_SIZE);
+        },
+    );
+}
+
+#[test]
+fn fixed_size_block_over_linked_list() {
+    run_fixed_size_block(LinkedListAllocator::new);
+}
+
+#[test]
+fn fixed_size_block_over_buddy() {
+    run_fixed_size_block(BuddyAllocator::new);
+}
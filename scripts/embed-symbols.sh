@@ -0,0 +1,50 @@
+#!/bin/sh
+# Writes the symbol table of a linked kernel into its `.symbol_table` section, which `backtrace` reads to
+# print `function+offset` for every frame. One line per function, sorted by start address:
+#
+#     <start address> <size> <demangled name without hash>
+#
+# The section keeps its size (`SYMBOL_TABLE_SIZE` in src/backtrace.rs), so no address in the image moves.
+# Uses the LLVM tools if installed (`rustup component add llvm-tools-preview` and `cargo install
+# cargo-binutils` provide `rust-nm` and `rust-objcopy`), otherwise the binutils ones.
+set -e
+
+kernel="$1"
+
+find_tool() {
+    for tool in "$@"; do
+        if command -v "$tool" >/dev/null 2>&1; then
+            echo "$tool"
+            return
+        fi
+    done
+}
+
+nm=$(find_tool llvm-nm rust-nm nm)
+objcopy=$(find_tool llvm-objcopy rust-objcopy objcopy)
+if [ -z "$nm" ] || [ -z "$objcopy" ]; then
+    echo "embed-symbols: nm or objcopy not found, backtraces will show plain addresses" >&2
+    exit 0
+fi
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+"$objcopy" --dump-section .symbol_table="$tmp/reserved" "$kernel"
+reserved=$(wc -c <"$tmp/reserved")
+
+# functions with a size only (t, T and weak W), the `::h<hash>` suffix of legacy mangling carries no information
+"$nm" --defined-only --numeric-sort --print-size --demangle "$kernel" |
+    sed -n -E 's/^([0-9a-f]+) ([0-9a-f]*[1-9a-f][0-9a-f]*) [tTW] (.*)$/\1 \2 \3/p' |
+    sed -E 's/::h[0-9a-f]{16}$//' >"$tmp/table"
+
+needed=$(wc -c <"$tmp/table")
+if [ "$needed" -ge "$reserved" ]; then
+    echo "embed-symbols: the table needs $needed bytes, only $reserved are reserved" \
+        "(SYMBOL_TABLE_SIZE in src/backtrace.rs)" >&2
+    exit 1
+fi
+
+# pad with the NUL bytes that end the table
+dd if=/dev/zero bs=1 count=$((reserved - needed)) 2>/dev/null >>"$tmp/table"
+"$objcopy" --update-section .symbol_table="$tmp/table" "$kernel"
@@ -0,0 +1,13 @@
+#!/bin/sh
+# Lints and runs the tests of `enigma-core` on the host, no QEMU needed. Extra arguments are passed on to
+# `cargo test`, e.g. `--test model`.
+#
+# `.cargo/config.toml` builds `core` and `alloc` from source for the kernel target, which also applies
+# here, so `std` and the unwinder are built from source for the host target as well.
+set -e
+
+host=$(rustc -vV | sed -n 's/^host: //p')
+manifest="$(dirname "$0")/../enigma-core/Cargo.toml"
+cargo clippy --manifest-path "$manifest" --target "$host" -Z build-std=std,panic_unwind --all-targets \
+    -- -D warnings
+cargo test --manifest-path "$manifest" --target "$host" -Z build-std=std,panic_unwind "$@"
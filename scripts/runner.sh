@@ -0,0 +1,7 @@
+#!/bin/sh
+# Cargo runner for the kernel target (see `.cargo/config.toml`): embeds the symbol table into the linked
+# kernel, then boots it with `bootimage runner` like before.
+set -e
+
+"$(dirname "$0")/embed-symbols.sh" "$1"
+exec bootimage runner "$@"
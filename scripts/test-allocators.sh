@@ -0,0 +1,9 @@
+#!/bin/sh
+# Runs the heap integration tests once with every global allocator (see the `alloc-*` features in
+# Cargo.toml). Extra arguments are passed on to `cargo test`, e.g. `--test slab`.
+set -e
+
+for allocator in bump linked-list fixed-block buddy; do
+    echo "==> alloc-$allocator"
+    cargo test --features "alloc-$allocator" --test heap_allocation "$@"
+done
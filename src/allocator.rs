@@ -1,34 +1,117 @@
+pub mod buddy;
 pub mod bump;
+#[cfg(feature = "debug-heap")]
+mod debug;
 pub mod fixed_size_block;
+pub mod leak;
 pub mod linked_list;
+pub mod oom;
+pub mod slab;
+pub mod stats;
 
-use linked_list::LinkedListAllocator;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use enigma_core::allocator::align_up;
+use oom::OomAction;
 use spin::{Mutex, MutexGuard};
+use stats::{Counters, HeapStats, HeapUsage};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-use self::fixed_size_block::FixedSizeBlockAllocator;
+// The allocator designs themselves don't depend on the hardware and live in `enigma_core`, where they are
+// tested on the host. Growing the heap and locking are added here.
+pub use enigma_core::allocator::FallbackAllocator;
+
+#[allow(unused_imports)] // only the allocator selected by the `alloc-*` feature is used
+use self::{
+    buddy::BuddyAllocator, bump::BumpAllocator, fixed_size_block::FixedSizeBlockAllocator,
+    linked_list::LinkedListAllocator,
+};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Size of the heap that `init_heap` maps up front, rounded up to whole pages.
+///
+/// Set at build time through the `ENIGMA_HEAP_SIZE` environment variable, in bytes or with a `K`, `M`
+/// or `G` suffix (e.g. `ENIGMA_HEAP_SIZE=4M cargo run`). Defaults to 100 KiB.
+pub const HEAP_SIZE: usize = match option_env!("ENIGMA_HEAP_SIZE") {
+    Some(size) => parse_size(size),
+    None => 100 * 1024,
+};
+// Virtual address range reserved for the heap, pages in it are only mapped when the heap grows
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB, far more than physical RAM
+// Minimum amount of memory mapped at once when the heap grows
+const HEAP_GROW_STEP: usize = 64 * 1024; // 64 KiB
+
+const _: () = assert!(HEAP_SIZE > 0 && HEAP_SIZE <= HEAP_MAX_SIZE, "invalid ENIGMA_HEAP_SIZE");
+
+/// Parses a size like `4096`, `64K` or `2M` at compile time, panicking (a compile error) if it is invalid.
+const fn parse_size(size: &str) -> usize {
+    let bytes = size.as_bytes();
+    let (digits, unit) = match bytes.last().copied() {
+        Some(b'K' | b'k') => (bytes.len() - 1, 1024),
+        Some(b'M' | b'm') => (bytes.len() - 1, 1024 * 1024),
+        Some(b'G' | b'g') => (bytes.len() - 1, 1024 * 1024 * 1024),
+        _ => (bytes.len(), 1),
+    };
+    assert!(digits > 0, "ENIGMA_HEAP_SIZE has no digits");
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits {
+        assert!(bytes[i].is_ascii_digit(), "ENIGMA_HEAP_SIZE is not a number");
+        value = value * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    match align_up(value * unit, 4096) {
+        Some(size) => size,
+        None => panic!("ENIGMA_HEAP_SIZE is too large"),
+    }
+}
+
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0); // ---> Bytes mapped from `HEAP_START` on
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::Relaxed);
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Maps the pages of the heap range `start..start + size` to newly allocated frames.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
     for page in page_range {
+        if mapper.translate_page(page).is_ok() {
+            continue; // mapped by an earlier attempt to grow that ran out of frames halfway
+        }
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
@@ -37,12 +120,39 @@ pub fn init_heap(
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
     }
+    Ok(())
+}
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+/// Maps at least `min_size` more bytes at the end of the heap.
+///
+/// Called by the allocator when it runs out of memory, with its lock held. Returns the number of bytes
+/// the heap grew by, `None` if the heap window is used up, no frames are left or the kernel memory is
+/// not installed yet (see `memory::install`).
+pub(crate) fn grow_heap(min_size: usize) -> Option<usize> {
+    let mapped = HEAP_MAPPED.load(Ordering::Relaxed);
+    let available = HEAP_MAX_SIZE - mapped;
+    if min_size > available {
+        return None;
     }
+    let size = align_up(min_size.max(HEAP_GROW_STEP), 4096)?.min(available);
 
-    Ok(())
+    let result = crate::memory::with_kernel_memory(|memory| {
+        map_heap_pages(
+            HEAP_START + mapped,
+            size,
+            &mut memory.mapper,
+            &mut memory.frame_allocator,
+        )
+    })?;
+    // A partially mapped range stays mapped, it is used again by the next attempt to grow
+    result.ok()?;
+    HEAP_MAPPED.store(mapped + size, Ordering::Relaxed);
+    Some(size)
+}
+
+/// Number of bytes of the heap window that are currently backed by physical frames.
+pub fn heap_mapped() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
 }
 
 /// A wraper around spin::Mutex to permit trait implementations.
@@ -57,33 +167,214 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<A> {
-        // So no data race can occur in multithreaded contexts
-        self.inner.lock()
+    /// Locks the allocator with interrupts disabled until the returned guard is dropped.
+    ///
+    /// Panics if the allocator is already locked. The kernel runs on a single CPU and nothing can
+    /// interrupt the holder of the lock, so the lock is only ever taken twice when the allocator is
+    /// re-entered, e.g. by an allocation from inside the allocator. Spinning would never end.
+    pub fn lock(&self) -> LockedGuard<A> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        // So no data race can occur in multithreaded contexts and no interrupt handler can allocate
+        // while the allocator is halfway through an allocation
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => LockedGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            },
+            None => panic!("allocator re-entered: the heap is already locked on this CPU"),
+        }
+    }
+}
+
+/// Access to a `Locked` allocator, interrupts stay disabled as long as it lives.
+pub struct LockedGuard<'a, A> {
+    guard: ManuallyDrop<MutexGuard<'a, A>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
     }
 }
 
-/// Align the given address `addr` upwards to alignment `align`.
-fn align_up(addr: usize, align: usize) -> usize {
-    // let remainder = addr % alig;
-    // if remainder == 0 {
-    //     addr
-    // } else {
-    //     addr - remainder + alig
-    // }
-    (addr + align - 1) & !(align - 1)
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
 }
 
+impl<A> Drop for LockedGuard<'_, A> {
+    fn drop(&mut self) {
+        // Unlock before interrupts come back, a pending interrupt may allocate right away
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// The global allocator: one of the allocator designs, plus the bookkeeping that is the same for all of
+/// them (statistics, leak tracking and with the `debug-heap` feature red zones and poisoning).
+pub struct KernelAllocator<A> {
+    heap: Locked<A>,
+    counters: Counters,
+}
+
+impl<A> KernelAllocator<A> {
+    pub const fn new(heap: A) -> Self {
+        Self {
+            heap: Locked::new(heap),
+            counters: Counters::new(),
+        }
+    }
+
+    pub fn lock(&self) -> LockedGuard<A> {
+        self.heap.lock()
+    }
+}
+
+impl<A: HeapUsage> KernelAllocator<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    #[cfg(not(feature = "debug-heap"))]
+    unsafe fn alloc_from_heap(&self, layout: Layout) -> *mut u8 {
+        self.heap.alloc(layout)
+    }
+
+    /// Allocates `layout` padded with red zones, see `debug`.
+    #[cfg(feature = "debug-heap")]
+    unsafe fn alloc_from_heap(&self, layout: Layout) -> *mut u8 {
+        match debug::padded_layout(&layout) {
+            Some(padded) => {
+                let block = self.heap.alloc(padded);
+                if block.is_null() {
+                    block
+                } else {
+                    debug::allocated(block, &layout)
+                }
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Reports the failed allocation and lets the out-of-memory hook decide what happens next.
+    fn out_of_memory(&self, layout: &Layout, attempt: usize) -> OomAction {
+        self.counters.failed();
+        {
+            // Printing only formats to serial and never allocates, so the heap can stay locked
+            let heap = self.heap.lock();
+            let stats = self.counters.snapshot(ALLOCATOR_NAME, &*heap);
+            oom::report(layout, attempt, &stats, &*heap);
+        }
+        // The hook runs unlocked, it may free memory
+        oom::run_hook(layout, attempt)
+    }
+}
+
+unsafe impl<A: HeapUsage> GlobalAlloc for KernelAllocator<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.alloc_from_heap(layout);
+        let mut attempt = 1;
+        while ptr.is_null() {
+            match self.out_of_memory(&layout, attempt) {
+                OomAction::Retry => ptr = self.alloc_from_heap(layout),
+                _ => return ptr::null_mut(),
+            }
+            attempt += 1;
+        }
+
+        self.counters.allocated(layout.size());
+        leak::allocated(ptr, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        leak::deallocated(ptr);
+        #[cfg(not(feature = "debug-heap"))]
+        self.heap.dealloc(ptr, layout);
+        #[cfg(feature = "debug-heap")]
+        {
+            let block = debug::deallocated(ptr, &layout);
+            self.heap.dealloc(block, debug::padded_layout(&layout).unwrap());
+        }
+        self.counters.deallocated(layout.size());
+    }
+}
+
+/// Returns the current statistics of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    // Reading the usage must not allocate, the allocator stays locked meanwhile
+    let heap = ALLOCATOR.lock();
+    ALLOCATOR.counters.snapshot(ALLOCATOR_NAME, &*heap)
+}
+
+/// Prints the heap statistics and the free lists over serial.
+pub fn dump_heap() {
+    let heap = ALLOCATOR.lock();
+    crate::serial_print!("{}", ALLOCATOR.counters.snapshot(ALLOCATOR_NAME, &*heap));
+    crate::serial_println!("free lists:");
+    let _ = heap.write_free_lists(&mut oom::SerialWriter);
+}
+
+// The global allocator is chosen with one of the `alloc-bump`, `alloc-linked-list`, `alloc-fixed-block` or
+// `alloc-buddy` cargo features, without any of them the fixed-size block allocator is used.
+const _: () = assert!(
+    cfg!(feature = "alloc-bump") as u8
+        + cfg!(feature = "alloc-linked-list") as u8
+        + cfg!(feature = "alloc-fixed-block") as u8
+        + cfg!(feature = "alloc-buddy") as u8
+        <= 1,
+    "only one of the `alloc-*` features can be enabled"
+);
+
 // To tells rust compiler which allocator instance it should use as global heap
 // allocator.the attribute is only applicable to a static that implements the
 // GlobalAlloc trait
-#[global_allocator]
-// `LockedHeap` uses the spinning_top::Spinlock type for synchronization. This is required because multiple threads
+// `KernelAllocator` locks the allocator with a spin::Mutex for synchronization. This is required because multiple threads
 // could access the ALLOCATOR static at the same time. As always, when using a spinlock or a mutex, we need
-// to be careful to not accidentally cause a deadlock. This means that we shouldn’t perform any allocations
-// in interrupt handlers, since they can run at an arbitrary time and might interrupt an in-progress
-// allocation.
-//static ALLOCATOR: LockedHeap = LockedHeap::empty(); // -> linked_list_crate allocator
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new()); // BumpAllocator
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+// to be careful to not accidentally cause a deadlock: an interrupt handler that allocates while it
+// interrupted an in-progress allocation would spin forever. `Locked::lock` therefore disables interrupts
+// while the lock is held, so interrupt handlers may allocate, and panics instead of spinning if the
+// allocator is re-entered anyway.
+#[cfg(feature = "alloc-bump")]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator<BumpAllocator> = KernelAllocator::new(BumpAllocator::new());
+#[cfg(feature = "alloc-bump")]
+pub const ALLOCATOR_NAME: &str = "bump";
+
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator<LinkedListAllocator> =
+    KernelAllocator::new(LinkedListAllocator::new());
+#[cfg(feature = "alloc-linked-list")]
+pub const ALLOCATOR_NAME: &str = "linked list";
+
+#[cfg(feature = "alloc-buddy")]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator<BuddyAllocator> = KernelAllocator::new(BuddyAllocator::new());
+#[cfg(feature = "alloc-buddy")]
+pub const ALLOCATOR_NAME: &str = "buddy";
+
+// Small allocations from the block lists, everything else from the buddy allocator
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-buddy"
+)))]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator<FixedSizeBlockAllocator<BuddyAllocator>> =
+    KernelAllocator::new(FixedSizeBlockAllocator::with_fallback(BuddyAllocator::new()));
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-buddy"
+)))]
+pub const ALLOCATOR_NAME: &str = "fixed-size block";
@@ -0,0 +1,21 @@
+// The buddy allocator is hardware independent and lives in `enigma_core`, this puts it behind the
+// kernel heap lock and grows the kernel heap when it runs out of memory.
+
+pub use enigma_core::allocator::buddy::*;
+
+use super::{grow_heap, FallbackAllocator, Locked};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate_or_grow(layout, grow_heap)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new(ptr).unwrap();
+        self.lock().deallocate(ptr, layout)
+    }
+}
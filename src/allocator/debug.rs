@@ -0,0 +1,104 @@
+// Debug Heap (`debug-heap` feature):
+// Bugs like use-after-free or writing past the end of an allocation corrupt the free lists that our
+// allocators keep inside the unused memory, and the crash only happens much later in the allocator. In
+// debug mode every allocation is padded: a header and a red zone in front, a red zone behind. The red
+// zones are filled with a canary value that is checked when the memory is freed, so an overrun is caught
+// at the `dealloc` of the overrun allocation. The header records the layout and whether the allocation
+// was freed, which catches double frees and frees with the wrong `Layout`.
+
+// Fresh memory is filled with 0xCD and freed memory with 0xDD, so reading uninitialized or freed memory
+// gives values that are easy to spot in a crash report.
+
+//   | free list node | header | red zone |  user memory  | red zone |
+//   ^ block start                        ^ returned pointer
+
+use super::align_up;
+use core::{alloc::Layout, mem, ptr, slice};
+
+/// Bytes the inner allocator may overwrite with its free list node when the block is freed.
+const FREE_LIST_NODE: usize = 16;
+const RED_ZONE: usize = 16;
+const CANARY: u8 = 0xFD;
+const FRESH: u8 = 0xCD;
+const FREED: u8 = 0xDD;
+const ALLOCATED_MAGIC: u64 = 0xA110_CA7E_DA11_0C00;
+const FREED_MAGIC: u64 = 0xF4EE_DF4E_EDF4_EE00;
+
+/// Stored directly in front of the front red zone.
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// Offset of the returned pointer from the start of the padded block.
+fn front_padding(layout: &Layout) -> usize {
+    // a few bytes rounded up to at most the largest power of two, which can't overflow
+    align_up(FREE_LIST_NODE + mem::size_of::<Header>() + RED_ZONE, layout.align()).unwrap()
+}
+
+/// The layout actually requested from the allocator for a user `layout`, `None` if it overflows.
+pub(super) fn padded_layout(layout: &Layout) -> Option<Layout> {
+    let size = front_padding(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align().max(mem::align_of::<Header>())).ok()
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(RED_ZONE + mem::size_of::<Header>()) as *mut Header
+}
+
+/// Prepares a freshly allocated padded block and returns the pointer handed to the user.
+pub(super) unsafe fn allocated(block: *mut u8, layout: &Layout) -> *mut u8 {
+    let ptr = block.add(front_padding(layout));
+    header(ptr).write_unaligned(Header {
+        magic: ALLOCATED_MAGIC,
+        size: layout.size(),
+        align: layout.align(),
+    });
+    ptr::write_bytes(ptr.sub(RED_ZONE), CANARY, RED_ZONE);
+    ptr::write_bytes(ptr, FRESH, layout.size());
+    ptr::write_bytes(ptr.add(layout.size()), CANARY, RED_ZONE);
+    ptr
+}
+
+/// Checks an allocation that is about to be freed and returns the start of its padded block.
+///
+/// Panics with the pointer and layout if the allocation was already freed, is freed with a different
+/// layout or if one of its red zones was overwritten.
+pub(super) unsafe fn deallocated(ptr: *mut u8, layout: &Layout) -> *mut u8 {
+    let mut header_value = header(ptr).read_unaligned();
+    match header_value.magic {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => panic!("debug heap: double free of {:p} ({:?})", ptr, layout),
+        _ => panic!(
+            "debug heap: free of {:p} ({:?}), which was not allocated or its header was overwritten",
+            ptr, layout
+        ),
+    }
+    if header_value.size != layout.size() || header_value.align != layout.align() {
+        panic!(
+            "debug heap: {:p} freed with {:?}, but allocated with size {} and align {}",
+            ptr, layout, header_value.size, header_value.align
+        );
+    }
+    if !is_canary(ptr.sub(RED_ZONE)) {
+        panic!("debug heap: buffer underflow in front of {:p} ({:?})", ptr, layout);
+    }
+    if !is_canary(ptr.add(layout.size())) {
+        panic!("debug heap: buffer overflow behind {:p} ({:?})", ptr, layout);
+    }
+
+    header_value.magic = FREED_MAGIC;
+    header(ptr).write_unaligned(header_value);
+    ptr::write_bytes(ptr, FREED, layout.size());
+    ptr.sub(front_padding(layout))
+}
+
+unsafe fn is_canary(red_zone: *const u8) -> bool {
+    slice::from_raw_parts(red_zone, RED_ZONE)
+        .iter()
+        .all(|&byte| byte == CANARY)
+}
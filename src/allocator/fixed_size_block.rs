@@ -1,112 +1,17 @@
-// The idea behind a fixed-size block allocator is the following: Instead of allocating exactly as much memory
-// as requested, we define a small number of block sizes and round up each allocation to the next
-// block size. For example, with block sizes of 16, 64, and 512 bytes, an allocation of 4 bytes would return
-// a 16-byte block, an allocation of 48 bytes a 64-byte block, and an allocation of 128 bytes a 512-byte block.
+// The fixed-size block allocator is hardware independent and lives in `enigma_core`, this puts it behind
+// the kernel heap lock and lets its fallback allocator grow the kernel heap.
 
-// Like the linked list allocator, we keep track of the unused memory by creating a linked list in the
-// unused memory. However, instead of using a single list with different block sizes, we create a separate list
-// for each size class. Each list then only stores blocks of a single size.
+pub use enigma_core::allocator::fixed_size_block::*;
 
-use core::{
-    alloc::{GlobalAlloc, Layout},
-    mem,
-    ptr::{self, NonNull},
-};
+use super::{grow_heap, FallbackAllocator, Locked};
+use core::alloc::{GlobalAlloc, Layout};
 
-use super::Locked;
-
-/// The block sizes to use.
-///
-/// The sizes must each be power of 2 because they are also used as
-/// the block alignment (alignments must be alwyas powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
-
-struct ListNode {
-    next: Option<&'static mut ListNode>,
-}
-
-pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap, // Using Crate's because our doesnot perform mergeing blocks
-}
-
-impl FixedSizeBlockAllocator {
-    /// Creates an empty FixedSizeBlockAllocator
-    pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
-        Self {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
-        }
-    }
-
-    /// Initializes the allocator with the given heap bounds
-    ///
-    ///
-    /// This function is unsafe because the caller must gurantee that the given
-    /// heap bounds are valid and that the heap is unused. This methods must be
-    /// called only once
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.fallback_allocator.init(heap_start, heap_size);
-    }
-
-    /// Allocates using the fallback allocator.
-    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
-        }
-    }
-}
-
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+unsafe impl<F: FallbackAllocator> GlobalAlloc for Locked<FixedSizeBlockAllocator<F>> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    // no block exists in list => allocate new block
-                    let block_size = BLOCK_SIZES[index];
-                    // only works if all block sizes are a power of 2
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+        self.lock().allocate(layout, grow_heap)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // Verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
-            }
-        }
+        self.lock().deallocate(ptr, layout)
     }
 }
-
-/// Choose an appropriate block size for the given layout.
-///
-/// Returns an index into the `BLOCK_SIZES` array
-fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
-}
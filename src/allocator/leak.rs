@@ -0,0 +1,133 @@
+// Leak Tracking:
+// While tracking is on, every allocation is recorded together with its layout and the return addresses of
+// the functions that led to it, and the record is removed again when the memory is freed. Whatever is
+// left at a checkpoint was allocated after `start` and never freed. The records live in a fixed table,
+// because the allocator can't allocate memory for its own bookkeeping. Interrupt handlers may allocate, so
+// the table is only locked with interrupts disabled.
+
+use crate::{backtrace, serial_println};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Number of live allocations that can be recorded at once.
+const MAX_RECORDS: usize = 512;
+/// Number of return addresses recorded per allocation.
+pub const CALLER_DEPTH: usize = 4;
+
+/// A live allocation made while tracking was on.
+#[derive(Debug, Clone, Copy)]
+pub struct LeakRecord {
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    pub callers: [usize; CALLER_DEPTH], // ---> Return addresses, innermost first, 0 if the stack ended
+}
+
+struct LeakTable {
+    records: [Option<LeakRecord>; MAX_RECORDS],
+    dropped: usize, // ---> Allocations not recorded because the table was full
+}
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TABLE: Mutex<LeakTable> = Mutex::new(LeakTable {
+    records: [None; MAX_RECORDS],
+    dropped: 0,
+});
+
+/// Forgets all records and starts recording allocations.
+pub fn start() {
+    without_interrupts(|| {
+        let mut table = TABLE.lock();
+        table.records = [None; MAX_RECORDS];
+        table.dropped = 0;
+    });
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording allocations, the existing records are kept.
+pub fn stop() {
+    TRACKING.store(false, Ordering::SeqCst);
+}
+
+/// Prints every allocation made since `start` that is still alive over serial and returns their number.
+pub fn checkpoint() -> usize {
+    // Nothing may be allocated while the table is locked, formatting to serial doesn't allocate
+    without_interrupts(|| {
+        let table = TABLE.lock();
+        let leaks = table.records.iter().flatten().count();
+        serial_println!("leak checkpoint: {} live allocations", leaks);
+        for record in table.records.iter().flatten() {
+            serial_println!(
+                "  {:#x}: {} bytes (align {}) from {:x?}",
+                record.ptr,
+                record.size,
+                record.align,
+                record.callers
+            );
+        }
+        if table.dropped > 0 {
+            serial_println!(
+                "  {} allocations were not recorded, the table was full",
+                table.dropped
+            );
+        }
+        leaks
+    })
+}
+
+/// Calls `f` with every live record, `f` must not allocate.
+pub fn for_each_record(mut f: impl FnMut(&LeakRecord)) {
+    without_interrupts(|| TABLE.lock().records.iter().flatten().for_each(|record| f(record)));
+}
+
+/// Called by the `KernelAllocator` for every successful allocation.
+#[inline(always)]
+pub(super) fn allocated(ptr: *mut u8, layout: Layout) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let record = LeakRecord {
+        ptr: ptr as usize,
+        size: layout.size(),
+        align: layout.align(),
+        callers: callers(),
+    };
+    without_interrupts(|| {
+        let mut table = TABLE.lock();
+        match table.records.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(record),
+            None => table.dropped += 1,
+        }
+    });
+}
+
+/// Called by the `KernelAllocator` for every deallocation.
+pub(super) fn deallocated(ptr: *mut u8) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    without_interrupts(|| {
+        let mut table = TABLE.lock();
+        let slot = table
+            .records
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(record) if record.ptr == ptr as usize));
+        if let Some(slot) = slot {
+            *slot = None;
+        }
+    });
+}
+
+/// The first return addresses on the stack, see `backtrace`.
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    for (caller, address) in callers.iter_mut().zip(backtrace::frames(backtrace::frame_pointer())) {
+        *caller = address as usize;
+    }
+    callers
+}
@@ -0,0 +1,75 @@
+// Out of Memory:
+// Every allocator grows the heap by itself when it runs out of free memory, so a null pointer from it means
+// that the heap window or the physical frames are used up. Without a hook, `handle_alloc_error` then panics
+// with nothing more than the requested size. Instead the `KernelAllocator` prints the failing layout, the
+// heap statistics and the free lists over serial and asks the out-of-memory hook what to do next. The
+// default hook fails the allocation, so `try_reserve` and co. still get their error and everything else
+// panics in `handle_alloc_error`. Setting the `shutdown` hook instead exits QEMU with
+// `QemuExitCode::OutOfMemory`, so a run that ran out of memory can be told apart from a failing test.
+
+use super::stats::{HeapStats, HeapUsage};
+use crate::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::{alloc::Layout, fmt};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// What the `KernelAllocator` does after an allocation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    Retry,    // ---> Allocate again, the allocator grows the heap if the hook freed frames
+    Fail,     // ---> Return a null pointer, `handle_alloc_error` panics for `Box`, `Vec` and co.
+    Shutdown, // ---> Exit QEMU with `QemuExitCode::OutOfMemory`
+}
+
+/// Called with the failing layout and the number of the attempt (starting at 1).
+pub type OomHook = fn(&Layout, usize) -> OomAction;
+
+static HOOK: Mutex<OomHook> = Mutex::new(fail);
+
+/// Replaces the out-of-memory hook and returns the previous one.
+///
+/// The hook runs without the allocator being locked, so it may free memory (e.g. `SlabCache::reclaim`
+/// to return frames) but it must not allocate. A hook that always returns `Retry` loops forever.
+pub fn set_hook(hook: OomHook) -> OomHook {
+    without_interrupts(|| core::mem::replace(&mut *HOOK.lock(), hook))
+}
+
+/// The default hook.
+pub fn fail(_layout: &Layout, _attempt: usize) -> OomAction {
+    OomAction::Fail
+}
+
+/// Opt-in hook that shuts QEMU down on the first failure, even for fallible allocations.
+pub fn shutdown(_layout: &Layout, _attempt: usize) -> OomAction {
+    OomAction::Shutdown
+}
+
+/// Prints the failing layout, the heap statistics and the free lists over serial.
+pub(super) fn report(layout: &Layout, attempt: usize, stats: &HeapStats, heap: &impl HeapUsage) {
+    serial_println!("out of memory: allocation of {:?} failed (attempt {})", layout, attempt);
+    serial_print!("{}", stats);
+    serial_println!("free lists:");
+    let _ = heap.write_free_lists(&mut SerialWriter);
+}
+
+/// Asks the hook what to do after the failed allocation, shuts down right away for `Shutdown`.
+pub(super) fn run_hook(layout: &Layout, attempt: usize) -> OomAction {
+    let hook = without_interrupts(|| *HOOK.lock());
+    let action = hook(layout, attempt);
+    if action == OomAction::Shutdown {
+        serial_println!("shutting down");
+        exit_qemu(QemuExitCode::OutOfMemory);
+        crate::hlt_loop();
+    }
+    action
+}
+
+/// Lets `HeapUsage::write_free_lists` write directly to serial, nothing has to be buffered on the heap.
+pub(super) struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+// Slab Allocator:
+// Kernels create and destroy the same kinds of objects over and over. A slab cache keeps a pool of
+// objects of a single type: it takes whole pages (a "slab") from the frame allocator and cuts them into
+// objects of exactly the type's size, so nothing is lost to rounding up to a block size like in the
+// fixed-size block allocator.
+
+// Like in Bonwick's original design, objects are built by the cache's constructor when their slab is
+// created and go back to the cache in that constructed state, so expensive initialization is not repeated
+// for every allocation. Each slab keeps its own free list as an array of object indices behind its header,
+// so the free list doesn't overwrite the constructed objects. Slabs are kept in three lists (partially
+// used, full and empty), allocations are served from partial slabs first, and `reclaim` drops the objects
+// of empty slabs and returns their pages to the frame allocator.
+
+use super::align_up;
+use crate::memory::{self, buddy::MAX_ORDER};
+use core::{
+    alloc::Layout,
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use spin::Mutex;
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+
+const PAGE_SIZE: usize = 4096;
+/// A slab is made larger than one page until at least this many objects fit.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const NONE: u16 = u16::MAX; // ---> End of a slab's free list
+
+/// Placed at the start of every slab, followed by the free list and the objects.
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    frame: PhysFrame, // ---> First frame of the slab, to give it back
+    free_head: u16,   // ---> Index of the first free object
+    in_use: u16,
+}
+
+/// Sizes and offsets shared by all slabs of a cache.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    order: usize,        // ---> The slab is 2^order frames large
+    objects: usize,      // ---> Objects per slab
+    stride: usize,       // ---> Distance between objects (size rounded up to the alignment)
+    first_object: usize, // ---> Offset of the first object from the slab start
+}
+
+impl Geometry {
+    fn of<T>() -> Geometry {
+        let stride = Layout::new::<T>().pad_to_align().size();
+        assert!(stride > 0, "zero sized objects don't need a slab cache");
+        let free_list = mem::size_of::<SlabHeader>();
+        for order in 0..=MAX_ORDER {
+            let slab_size = PAGE_SIZE << order;
+            // one u16 free list entry per object, then the aligned objects
+            let mut objects = (slab_size - free_list) / (stride + mem::size_of::<u16>());
+            let first_object = loop {
+                let free_list_end = free_list + objects * mem::size_of::<u16>();
+                let first = align_up(free_list_end, mem::align_of::<T>()).unwrap();
+                if first + objects * stride <= slab_size {
+                    break first;
+                }
+                objects -= 1;
+            };
+            let objects = objects.min(NONE as usize);
+            if objects >= MIN_OBJECTS_PER_SLAB || order == MAX_ORDER {
+                assert!(objects > 0, "object too large for a slab");
+                return Geometry {
+                    order,
+                    objects,
+                    stride,
+                    first_object,
+                };
+            }
+        }
+        unreachable!()
+    }
+
+    fn slab_size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+}
+
+/// A doubly linked list of slabs.
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+}
+
+struct SlabLists {
+    partial: SlabList, // ---> Slabs with used and free objects, allocations come from here first
+    full: SlabList,
+    empty: SlabList, // ---> Kept until `reclaim`, all objects free (and constructed)
+    allocations: u64,
+    frees: u64,
+    reclaimed_slabs: u64,
+}
+
+/// A named pool of objects of type `T` that are carved out of page-sized slabs.
+///
+/// Objects are created with the cache's constructor when their slab is created, and are handed out again
+/// in whatever state they were returned in. Users must therefore leave an object in its constructed state
+/// before dropping its `SlabBox`.
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    lists: Mutex<SlabLists>,
+}
+
+// The slabs are only accessed with the lock held
+unsafe impl<T: Send> Send for SlabCache<T> {}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates an empty cache, slabs are only created on the first allocation.
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        Self {
+            name,
+            constructor,
+            lists: Mutex::new(SlabLists {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                empty: SlabList::new(),
+                allocations: 0,
+                frees: 0,
+                reclaimed_slabs: 0,
+            }),
+        }
+    }
+
+    /// Takes an object out of the cache, creating a new slab if all slabs are full.
+    ///
+    /// Returns `None` if no frames are left or the kernel memory is not installed yet (see
+    /// `memory::install`).
+    pub fn alloc(&self) -> Option<SlabBox<'_, T>> {
+        let geometry = Geometry::of::<T>();
+        let mut lists = self.lists.lock();
+        unsafe {
+            let slab = if !lists.partial.head.is_null() {
+                lists.partial.head
+            } else if !lists.empty.head.is_null() {
+                let slab = lists.empty.head;
+                lists.empty.remove(slab);
+                lists.partial.push(slab);
+                slab
+            } else {
+                let slab = self.create_slab(&geometry)?;
+                lists.partial.push(slab);
+                slab
+            };
+
+            let index = (*slab).free_head as usize;
+            (*slab).free_head = *free_list(slab).add(index);
+            (*slab).in_use += 1;
+            if (*slab).free_head == NONE {
+                lists.partial.remove(slab);
+                lists.full.push(slab);
+            }
+            lists.allocations += 1;
+
+            Some(SlabBox {
+                cache: self,
+                object: NonNull::new_unchecked(object(slab, &geometry, index)),
+            })
+        }
+    }
+
+    /// Puts an object back to the free list of its slab.
+    unsafe fn free(&self, ptr: *mut T) {
+        let geometry = Geometry::of::<T>();
+        // slabs are aligned to their size, so the header is found by rounding down
+        let slab = (ptr as usize & !(geometry.slab_size() - 1)) as *mut SlabHeader;
+        let index = (ptr as usize - slab as usize - geometry.first_object) / geometry.stride;
+
+        let mut lists = self.lists.lock();
+        if (*slab).free_head == NONE {
+            lists.full.remove(slab);
+            lists.partial.push(slab);
+        }
+        *free_list(slab).add(index) = (*slab).free_head;
+        (*slab).free_head = index as u16;
+        (*slab).in_use -= 1;
+        if (*slab).in_use == 0 {
+            lists.partial.remove(slab);
+            lists.empty.push(slab);
+        }
+        lists.frees += 1;
+    }
+
+    /// Allocates the pages of a new slab and constructs all of its objects.
+    unsafe fn create_slab(&self, geometry: &Geometry) -> Option<*mut SlabHeader> {
+        let frame = memory::with_kernel_memory(|memory| {
+            memory.frame_allocator.allocate_block(geometry.order)
+        })??;
+        let start = memory::phys_to_virt(frame.start_address());
+        // The physical memory mapping is aligned far beyond the largest slab
+        assert!(start.is_aligned(geometry.slab_size() as u64));
+
+        let slab = start.as_mut_ptr::<SlabHeader>();
+        slab.write(SlabHeader {
+            next: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            frame,
+            free_head: 0,
+            in_use: 0,
+        });
+        for index in 0..geometry.objects {
+            let next = if index + 1 < geometry.objects {
+                index as u16 + 1
+            } else {
+                NONE
+            };
+            free_list(slab).add(index).write(next);
+            object(slab, geometry, index).write((self.constructor)());
+        }
+        Some(slab)
+    }
+
+    /// Drops the objects of all empty slabs and gives their pages back to the frame allocator.
+    ///
+    /// Returns the number of slabs that were freed.
+    pub fn reclaim(&self) -> usize {
+        let geometry = Geometry::of::<T>();
+        let mut lists = self.lists.lock();
+        let mut reclaimed = 0;
+        while !lists.empty.head.is_null() {
+            unsafe {
+                let slab = lists.empty.head;
+                lists.empty.remove(slab);
+                for index in 0..geometry.objects {
+                    ptr::drop_in_place(object(slab, &geometry, index));
+                }
+                let frame = (*slab).frame;
+                memory::with_kernel_memory(|memory| {
+                    memory.frame_allocator.deallocate_block(frame, geometry.order)
+                })
+                .expect("kernel memory not installed");
+            }
+            reclaimed += 1;
+        }
+        lists.reclaimed_slabs += reclaimed as u64;
+        reclaimed
+    }
+
+    /// Returns the current statistics of this cache.
+    pub fn stats(&self) -> SlabStats {
+        let geometry = Geometry::of::<T>();
+        let lists = self.lists.lock();
+        let mut active_objects = lists.full.len * geometry.objects;
+        let mut slab = lists.partial.head;
+        while !slab.is_null() {
+            unsafe {
+                active_objects += (*slab).in_use as usize;
+                slab = (*slab).next;
+            }
+        }
+        SlabStats {
+            name: self.name,
+            object_size: mem::size_of::<T>(),
+            objects_per_slab: geometry.objects,
+            slab_size: geometry.slab_size(),
+            slabs: lists.partial.len + lists.full.len + lists.empty.len,
+            empty_slabs: lists.empty.len,
+            active_objects,
+            allocations: lists.allocations,
+            frees: lists.frees,
+            reclaimed_slabs: lists.reclaimed_slabs,
+        }
+    }
+
+    /// Name given to the cache in `new`.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Start of the free list array of a slab, one entry per object with the index of the next free one.
+unsafe fn free_list(slab: *mut SlabHeader) -> *mut u16 {
+    slab.add(1) as *mut u16
+}
+
+/// Address of the object with the given index in a slab.
+unsafe fn object<T>(slab: *mut SlabHeader, geometry: &Geometry, index: usize) -> *mut T {
+    let offset = geometry.first_object + index * geometry.stride;
+    VirtAddr::from_ptr(slab).as_mut_ptr::<u8>().add(offset) as *mut T
+}
+
+/// Statistics of a `SlabCache`, see `SlabCache::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slab_size: usize, // ---> Bytes, a power of two multiple of the page size
+    pub slabs: usize,
+    pub empty_slabs: usize,
+    pub active_objects: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub reclaimed_slabs: u64,
+}
+
+impl SlabStats {
+    /// Objects in all slabs, used or not.
+    pub fn total_objects(&self) -> usize {
+        self.slabs * self.objects_per_slab
+    }
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}/{} objects of {} bytes, {} slabs of {} KiB ({} empty), {} allocs, {} frees",
+            self.name,
+            self.active_objects,
+            self.total_objects(),
+            self.object_size,
+            self.slabs,
+            self.slab_size / 1024,
+            self.empty_slabs,
+            self.allocations,
+            self.frees
+        )
+    }
+}
+
+/// An object borrowed from a `SlabCache`, returned to the cache when dropped.
+pub struct SlabBox<'a, T> {
+    cache: &'a SlabCache<T>,
+    object: NonNull<T>,
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.object.as_ptr()) };
+    }
+}
@@ -0,0 +1,136 @@
+// Heap Statistics:
+// The global allocator is wrapped in a `KernelAllocator` that counts every allocation, so the numbers are
+// the same whichever allocator design is selected. How much of the heap is free and how fragmented it is
+// can only be answered by the allocator itself, which it does through the `HeapUsage` trait.
+
+use super::fixed_size_block::BLOCK_SIZES;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+pub use enigma_core::allocator::stats::{BlockStats, HeapUsage};
+
+/// Allocation counters kept by the `KernelAllocator`.
+pub(super) struct Counters {
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    failed_allocations: AtomicU64,
+}
+
+impl Counters {
+    pub(super) const fn new() -> Self {
+        Self {
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            failed_allocations: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn allocated(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        let in_use = self.bytes_in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+    }
+
+    pub(super) fn deallocated(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_use.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    pub(super) fn failed(&self) {
+        self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Combines the counters with the usage reported by the allocator.
+    pub(super) fn snapshot(&self, allocator: &'static str, usage: &impl HeapUsage) -> HeapStats {
+        HeapStats {
+            allocator,
+            heap_size: super::heap_mapped(),
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            failed_allocations: self.failed_allocations.load(Ordering::Relaxed),
+            free_bytes: usage.free_bytes(),
+            largest_free_block: usage.largest_free_block(),
+            blocks: usage.block_stats(),
+        }
+    }
+}
+
+/// A snapshot of the state of the kernel heap, see `allocator::heap_stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub allocator: &'static str, // ---> Name of the selected global allocator
+    pub heap_size: usize,        // ---> Bytes mapped for the heap
+    pub bytes_in_use: usize,     // ---> Requested bytes of all live allocations
+    pub peak_bytes_in_use: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    pub failed_allocations: u64,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+    pub blocks: Option<BlockStats>,
+}
+
+impl HeapStats {
+    /// Allocations that were not freed yet.
+    pub fn live_allocations(&self) -> u64 {
+        self.allocations - self.deallocations
+    }
+
+    /// How much of the free memory can't be used for a single allocation, in percent.
+    ///
+    /// 0 means all free memory is one block, values close to 100 mean it is split into many small pieces.
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free_block * 100 / self.free_bytes
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "heap ({}): {} bytes in use, peak {}, {} bytes mapped",
+            self.allocator, self.bytes_in_use, self.peak_bytes_in_use, self.heap_size
+        )?;
+        writeln!(
+            f,
+            "  {} allocations, {} frees, {} live, {} failed",
+            self.allocations,
+            self.deallocations,
+            self.live_allocations(),
+            self.failed_allocations
+        )?;
+        writeln!(
+            f,
+            "  {} bytes free, largest free block {} bytes, fragmentation {}%",
+            self.free_bytes,
+            self.largest_free_block,
+            self.fragmentation_percent()
+        )?;
+        if let Some(blocks) = &self.blocks {
+            for (index, size) in BLOCK_SIZES.iter().enumerate() {
+                writeln!(
+                    f,
+                    "  block {:>4}: {} allocations, {} free",
+                    size, blocks.allocations[index], blocks.free_blocks[index]
+                )?;
+            }
+            writeln!(
+                f,
+                "  fallback: {} allocations, {} bytes in use",
+                blocks.fallback_allocations, blocks.fallback_bytes_in_use
+            )?;
+        }
+        Ok(())
+    }
+}
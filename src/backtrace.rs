@@ -0,0 +1,166 @@
+// Stack Backtraces:
+// The kernel is built with frame pointers (`"frame-pointer": "always"` in the target json), so every
+// function starts with `push rbp; mov rbp, rsp`. `rbp` thus points to the saved `rbp` of the caller, with
+// the return address into the caller right above it, and following the saved `rbp`s walks the stack from
+// the innermost frame outwards. Nothing marks the end of the chain, so the walk stops as soon as the next
+// frame doesn't lie a bit further up the same stack. Code built without frame pointers (like the assembly
+// stubs) uses `rbp` as an ordinary register, so every frame is checked to be mapped before it is read and
+// the walk ends early on garbage.
+
+// Every address is printed as `function+offset`, looked up in the symbol table that `scripts/runner.sh`
+// writes into the `.symbol_table` section of the linked kernel before booting it. A kernel image built
+// without it (e.g. by `cargo bootimage`) only has the zeroes reserved here and prints plain addresses.
+
+use crate::memory;
+use core::{arch::asm, fmt};
+use enigma_core::symbols::SymbolTable;
+use x86_64::VirtAddr;
+
+/// Frames walked at most, a corrupted chain could otherwise loop.
+pub const MAX_DEPTH: usize = 32;
+/// Distance between two frames above which the chain is considered broken.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// Bytes reserved for the symbol table, `scripts/embed-symbols.sh` fails if the table doesn't fit.
+pub const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+
+#[used]
+#[link_section = ".symbol_table"]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// The symbol table embedded after linking, empty if there is none.
+pub fn symbols() -> SymbolTable<'static> {
+    // the compiler only knows the zeroes of link time, it must not trace the reference back to them
+    SymbolTable::new(core::hint::black_box(&SYMBOL_TABLE))
+}
+
+/// The frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// Iterates over the return addresses on the stack, innermost first, starting at the frame `rbp`.
+pub fn frames(rbp: u64) -> Frames {
+    Frames { rbp, depth: 0 }
+}
+
+pub struct Frames {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0
+            || self.rbp % 8 != 0
+            || self.depth == MAX_DEPTH
+            || !memory::is_mapped(VirtAddr::new_truncate(self.rbp), 16)
+        {
+            return None;
+        }
+        // [rbp] holds the caller's rbp, [rbp + 8] the return address
+        let (next, return_address) = unsafe {
+            let frame = self.rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+        // frames of callers lie above on the stack, anything else means the chain ended
+        self.rbp = if next <= self.rbp || next - self.rbp > MAX_FRAME_SIZE {
+            0
+        } else {
+            next
+        };
+        self.depth += 1;
+        (return_address != 0).then_some(return_address)
+    }
+}
+
+/// Prints `rip` and every return address found from `rbp` as `function+offset`, one frame per line.
+pub struct Backtrace {
+    pub rip: u64,
+    pub rbp: u64,
+}
+
+impl Backtrace {
+    /// The backtrace of the calling function.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let rip: u64;
+        unsafe { asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack)) };
+        Backtrace {
+            rip,
+            rbp: frame_pointer(),
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbols = symbols();
+        write!(f, "stack backtrace:")?;
+        write_frame(f, &symbols, 0, self.rip, self.rip)?;
+        for (i, address) in frames(self.rbp).enumerate() {
+            // a return address points behind the call, which may be the first byte of the next function
+            write_frame(f, &symbols, i + 1, address, address - 1)?;
+        }
+        if symbols.is_empty() {
+            write!(f, "\n  (no symbol table, run the kernel through scripts/runner.sh)")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_frame(
+    f: &mut fmt::Formatter,
+    symbols: &SymbolTable,
+    index: usize,
+    address: u64,
+    lookup: u64,
+) -> fmt::Result {
+    write!(f, "\n  {:>2}: {:#018x}", index, address)?;
+    match symbols.lookup(lookup) {
+        Some(symbol) => write!(f, " - {}+{:#x}", symbol.name, address - symbol.start),
+        None => Ok(()),
+    }
+}
+
+#[test_case]
+fn test_frames_reach_the_caller() {
+    #[inline(never)]
+    fn callee() -> u64 {
+        frames(frame_pointer()).next().unwrap()
+    }
+
+    #[inline(never)]
+    fn caller() -> u64 {
+        core::hint::black_box(callee()) // no tail call, the return address must point into `caller`
+    }
+
+    let return_address = caller();
+    let start = caller as usize as u64;
+    // the return address lies inside `caller`, which is only a few instructions long
+    assert!(return_address > start && return_address - start < 256);
+}
+
+#[test_case]
+fn test_symbol_of_return_address() {
+    #[inline(never)]
+    fn traced() -> u64 {
+        core::hint::black_box(frames(frame_pointer()).next().unwrap())
+    }
+
+    #[inline(never)]
+    fn caller() -> u64 {
+        core::hint::black_box(traced())
+    }
+
+    let return_address = caller();
+    // only embedded by `scripts/runner.sh`
+    if let Some(symbol) = symbols().lookup(return_address - 1) {
+        assert!(symbol.name.ends_with("test_symbol_of_return_address::caller"), "{}", symbol.name);
+    }
+}
@@ -0,0 +1,71 @@
+// QEMU Firmware Configuration:
+// QEMU hands extra data to the guest through the fw_cfg device, e.g. `-fw_cfg name=opt/enigma/x,string=y`
+// on the command line. On x86 it is driven through two I/O ports: writing a 16 bit key to the selector
+// port selects an item, and every read of the data port returns the next byte of it. Key 0 holds the
+// signature "QEMU", key 0x19 a directory of all named items ("files"), each with its size and its own key.
+// Multi-byte values in the directory are big-endian. On real hardware nothing answers on these ports.
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+
+const SIGNATURE_KEY: u16 = 0x0000;
+const FILE_DIR_KEY: u16 = 0x0019;
+const FILE_NAME_LEN: usize = 56;
+
+/// Returns whether the fw_cfg device is present, i.e. we run inside QEMU.
+pub fn is_present() -> bool {
+    let mut signature = [0; 4];
+    without_interrupts(|| unsafe {
+        select(SIGNATURE_KEY);
+        read(&mut signature);
+    });
+    &signature == b"QEMU"
+}
+
+/// Copies the fw_cfg file `name` into `buffer` and returns its length.
+///
+/// A file longer than `buffer` is cut off. `None` if there is no fw_cfg device or no such file.
+pub fn read_file(name: &str, buffer: &mut [u8]) -> Option<usize> {
+    if !is_present() {
+        return None;
+    }
+    // selecting and reading must not be interleaved with another reader
+    without_interrupts(|| unsafe {
+        select(FILE_DIR_KEY);
+        let mut count = [0; 4];
+        read(&mut count);
+
+        for _ in 0..u32::from_be_bytes(count) {
+            let mut size = [0; 4];
+            let mut key = [0; 2];
+            let mut reserved = [0; 2];
+            let mut file_name = [0; FILE_NAME_LEN];
+            read(&mut size);
+            read(&mut key);
+            read(&mut reserved);
+            read(&mut file_name);
+
+            let name_len = file_name.iter().position(|&byte| byte == 0).unwrap_or(FILE_NAME_LEN);
+            if &file_name[..name_len] == name.as_bytes() {
+                let len = (u32::from_be_bytes(size) as usize).min(buffer.len());
+                select(u16::from_be_bytes(key));
+                read(&mut buffer[..len]);
+                return Some(len);
+            }
+        }
+        None
+    })
+}
+
+unsafe fn select(key: u16) {
+    Port::<u16>::new(SELECTOR_PORT).write(key);
+}
+
+unsafe fn read(buffer: &mut [u8]) {
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for byte in buffer {
+        *byte = data.read();
+    }
+}
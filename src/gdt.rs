@@ -36,6 +36,8 @@ struct Selector {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; // Defining Double Fault Stack Index
+// Page faults caused by a stack overflow can't push their stack frame to the overflowed stack
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
 lazy_static! {
     //on x86_64 holds two stack tables( Interrupt Stack Table and Privilege Stack Table )
@@ -50,6 +52,13 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end // Writing Top address of stack because stacks on x86 grows downwards
         };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
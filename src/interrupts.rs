@@ -1,9 +1,12 @@
-use crate::{gdt, print, println, hlt_loop};
+use crate::thread::{self, switch::SavedContext};
 use lazy_static::lazy_static;
-use pc_keyboard::{Keyboard, layouts, ScancodeSet1, HandleControl, DecodedKey};
 use pic8259::ChainedPics; // Abstraction for Primary/Secondary PICs
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
 
 // offsets for PICs in range 32-47 because default are already occupied by CPU Exceptions
 pub const PIC_1_OFFSET: u8 = 32;
@@ -16,102 +19,93 @@ lazy_static! {
     // CPU reads IDT entry for Execption when Exception occurs
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        // Setiing handler function for exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Setiing handler functions for all CPU exceptions
+        exceptions::install(&mut idt);
+
+        // Register Timer Interrupt handler, enabled by default if  external interrupts are enabled.
+        // It enters through an assembly stub which saves all registers, so the handler can switch
+        // to another thread
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // Switch stack on double fault
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(thread::switch::timer_entry_addr());
+            idt[thread::switch::YIELD_VECTOR]
+                .set_handler_addr(thread::switch::yield_entry_addr());
         }
-        // Register Timer Interrupt handler, enabled by default if  external interrupts are enabled
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
 
-        // Register Keyboard Interrupt Handler
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // All other IRQ lines go through the common dispatch path, devices attach with
+        // `irq::register`
+        irq::install(&mut idt);
+
+        // Local APIC signals spurious interrupts on its own vector, they need no EOI
+        idt[usize::from(apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
         
-        // Setting Page Fault Handler
-        idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
 }
 
 pub fn init_idt() {
     IDT.load(); // Load IDT to CPU
-}
 
-// `x86-interrupt` FFI Ensures calling convention appropriate for exception handling
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("[EXCEPTION] BREAKPOINT\n{:#?}", stack_frame);
+    irq::register(InterruptIndex::Keyboard.irq_line(), keyboard_interrupt_handler)
+        .expect("failed to register keyboard handler");
+    // COM1 raises an interrupt for every received byte
+    irq::register(InterruptIndex::Serial.irq_line(), serial_interrupt_handler)
+        .expect("failed to register serial handler");
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-
-    println!("[EXCEPTION] PAGE FAULT");
-    println!("Accessed Address: {:?}",Cr2::read()); // Cr2 Register containes address that caused Fault        
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}",stack_frame);
-
-    hlt_loop(); // Continue only after resolving page Fault
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("[EXCEPTION] DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        // PICs require `end of interrupt` signal from handler so that it can know interrupt was
-        // handled and system is ready to receive next interrupt
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+/// Called from `thread::switch::timer_interrupt_entry` with the registers of the interrupted thread
+/// saved in `context`. Returns the context of the thread to resume.
+pub(crate) extern "C" fn timer_interrupt_handler(context: *mut SavedContext) -> *mut SavedContext {
+    irq::run_handlers(InterruptIndex::Timer.irq_line());
+    // Interrupt controller requires `end of interrupt` signal from handler so that it can know
+    // interrupt was handled and system is ready to receive next interrupt. Sent before switching,
+    // because the next thread resumes directly from its own stack
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
+    crate::testing::watchdog::check(unsafe { &*context });
+    thread::scheduler::schedule(context, true)
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt_handler(_line: u8) {
     use x86_64::instructions::port::Port;
     
     // NOTE : If we don't read key, next key press will not happen
 
-    // Create Keyboard object with US keyboard layout and the scancode set 1
-    // PS/2 keyboard emulate scancode set 1 (IBM XT)
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     // To know which key was pressed, we read from data port of PS/2 controller(keyboard interrupt
     // controller), which is I/O port `0x60`
     let mut port = Port::new(0x60);
 
-    // Scan code is data that most computer keyboards send to computer about keys been pressed
+    // Scan code is data that most computer keyboards send to computer about keys been pressed.
+    // Decoding happens later in a task, the handler only queues the raw byte
     let scan_code: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scan_code) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}",character),
-                DecodedKey::RawKey(key) => print!("{:?}",key),
-            }
-        }
-    }
+    crate::task::keyboard::add_scancode(scan_code);
+}
 
-    unsafe {
-        // Set EOI signal so next interrupt can be received
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+fn serial_interrupt_handler(_line: u8) {
+    crate::serial::drain_input(); // Reading the received bytes acknowledges the interrupt
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    irq::count_spurious();
+}
+
+/// Sends the `end of interrupt` signal for `vector` to whichever interrupt controller is in use.
+pub(crate) fn end_of_interrupt(vector: u8) {
+    match apic::mode() {
+        apic::Mode::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        },
+        apic::Mode::XApic | apic::Mode::X2Apic => apic::end_of_interrupt(),
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET, // (0 + 32) timer uses line 0 of primary PIC
-    Keyboard,             // (33) keyboard uses line 1 of primary PIC
+    Timer = PIC_1_OFFSET,      // (0 + 32) timer uses line 0 of primary PIC
+    Keyboard,                  // (33) keyboard uses line 1 of primary PIC
+    Serial = PIC_1_OFFSET + 4, // (36) COM1 uses line 4 of primary PIC
 }
 
 impl InterruptIndex {
@@ -123,6 +117,10 @@ impl InterruptIndex {
         // because we use it as index on idt
         usize::from(self.as_u8())
     }
+
+    fn irq_line(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
 }
 
 // Test Cases
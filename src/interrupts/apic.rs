@@ -0,0 +1,359 @@
+// APIC (Advanced Programmable Interrupt Controller):
+// The 8259 PICs only have 15 usable lines and can only deliver interrupts to one CPU. Modern systems
+// instead have a local APIC in every CPU core, which receives interrupts and also sends inter-processor
+// interrupts, and one or more I/O APICs, which receive the external interrupt lines and forward them to
+// local APICs according to their redirection table.
+
+// The local APIC registers are accessed through memory-mapped I/O (xAPIC) or, if the CPU supports it,
+// through model specific registers (x2APIC). The address of the I/O APIC and how the legacy ISA IRQs are
+// wired to its inputs is described by the MADT table of ACPI. The ISA timer for example usually arrives
+// at input 2 of the I/O APIC instead of 0 ("interrupt source override").
+
+use super::{irq, PIC_1_OFFSET};
+use crate::memory;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    registers::model_specific::Msr,
+    PhysAddr, VirtAddr,
+};
+
+/// Vector the local APIC uses for spurious interrupts, which must not be acknowledged with EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11; // ---> Global enable of the local APIC
+const APIC_BASE_X2APIC: u64 = 1 << 10; // ---> Switch the local APIC to x2APIC mode
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Local APIC register offsets in xAPIC mode, x2APIC uses MSR `0x800 + offset / 16`
+const LAPIC_ID: u32 = 0x20;
+const LAPIC_TASK_PRIORITY: u32 = 0x80;
+const LAPIC_EOI: u32 = 0xb0;
+const LAPIC_SPURIOUS: u32 = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+/// Default physical address of the first I/O APIC, used if there is no MADT.
+const DEFAULT_IO_APIC_ADDRESS: u64 = 0xfec0_0000;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    Pic = 0,    // ---> No APIC in use, the chained 8259 PICs deliver interrupts
+    XApic = 1,  // ---> Local APIC registers are memory-mapped
+    X2Apic = 2, // ---> Local APIC registers are accessed through MSRs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    NotSupported,      // ---> CPUID reports no local APIC
+    MappingFailed,     // ---> APIC registers could not be mapped, kernel memory not installed?
+    AlreadyInitialized,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Pic as u8);
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0); // ---> Virtual address of the xAPIC registers
+static IO_APIC_BASE: AtomicU64 = AtomicU64::new(0); // ---> Virtual address of the I/O APIC registers
+// I/O APIC input (GSI) and MADT flags of each ISA IRQ line
+static ISA_ROUTES: Mutex<[(u32, u16); 16]> = Mutex::new([(0, 0); 16]);
+
+/// Returns which interrupt controller currently delivers interrupts.
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::XApic,
+        2 => Mode::X2Apic,
+        _ => Mode::Pic,
+    }
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the local APIC and I/O APIC.
+///
+/// Needs the kernel memory (see `memory::install`) to map the APIC registers. If it returns an error
+/// the PICs stay in use, so the kernel keeps working on machines without an APIC.
+pub fn init() -> Result<Mode, ApicError> {
+    if mode() != Mode::Pic {
+        return Err(ApicError::AlreadyInitialized);
+    }
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    if features.edx & (1 << 9) == 0 {
+        return Err(ApicError::NotSupported);
+    }
+    let x2apic = features.ecx & (1 << 21) != 0;
+    let madt = Madt::find();
+
+    let io_apic = {
+        let phys = PhysAddr::new(madt.map_or(DEFAULT_IO_APIC_ADDRESS, |m| m.io_apic_address));
+        let virt = memory::map_mmio(phys, 0x20).ok_or(ApicError::MappingFailed)?;
+        IoApic { base: virt }
+    };
+
+    interrupts::without_interrupts(|| -> Result<Mode, ApicError> {
+        let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        let base = unsafe { base_msr.read() };
+        let mode = if x2apic {
+            // x2APIC can only be entered from the enabled xAPIC mode, going there directly from the disabled
+            // state raises #GP on some hardware
+            unsafe {
+                base_msr.write(base | APIC_BASE_ENABLE);
+                base_msr.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            }
+            Mode::X2Apic
+        } else {
+            let phys = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+            let virt = memory::map_mmio(phys, 0x400).ok_or(ApicError::MappingFailed)?;
+            LAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+            unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+            Mode::XApic
+        };
+        MODE.store(mode as u8, Ordering::Relaxed);
+
+        unsafe {
+            write_lapic(LAPIC_TASK_PRIORITY, 0); // accept all interrupt priorities
+            write_lapic(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+            disable_pics();
+        }
+
+        io_apic.mask_all();
+        IO_APIC_BASE.store(io_apic.base.as_u64(), Ordering::Relaxed);
+        {
+            let mut routes = ISA_ROUTES.lock();
+            for (irq, route) in (0u8..).zip(routes.iter_mut()) {
+                *route = madt.map_or((u32::from(irq), 0), |m| m.isa_override(irq));
+            }
+        }
+        // Keep delivering every line that already has handlers
+        for irq in 0..16 {
+            if irq::is_line_enabled(irq) {
+                set_isa_irq_masked(irq, false);
+            }
+        }
+        Ok(mode)
+    })
+}
+
+/// Masks or unmasks the I/O APIC input the ISA `irq` line is wired to.
+///
+/// Does nothing while the PICs are in use.
+pub(crate) fn set_isa_irq_masked(irq: u8, masked: bool) {
+    if mode() == Mode::Pic {
+        return;
+    }
+    let io_apic = IoApic {
+        base: VirtAddr::new(IO_APIC_BASE.load(Ordering::Relaxed)),
+    };
+    let (gsi, flags) = ISA_ROUTES.lock()[usize::from(irq)];
+    let mut entry = IoApic::redirection_entry(PIC_1_OFFSET + irq, flags, local_apic_id());
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    interrupts::without_interrupts(|| io_apic.write_redirection(gsi, entry));
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub(crate) fn end_of_interrupt() {
+    unsafe { write_lapic(LAPIC_EOI, 0) };
+}
+
+/// ID of the local APIC of the current CPU.
+fn local_apic_id() -> u8 {
+    match mode() {
+        Mode::X2Apic => unsafe { read_lapic(LAPIC_ID) as u8 },
+        _ => unsafe { (read_lapic(LAPIC_ID) >> 24) as u8 },
+    }
+}
+
+unsafe fn read_lapic(offset: u32) -> u32 {
+    match mode() {
+        Mode::X2Apic => Msr::new(X2APIC_MSR_BASE + offset / 16).read() as u32,
+        Mode::XApic => {
+            let base = LAPIC_BASE.load(Ordering::Relaxed);
+            ptr::read_volatile((base + u64::from(offset)) as *const u32)
+        }
+        Mode::Pic => 0,
+    }
+}
+
+unsafe fn write_lapic(offset: u32, value: u32) {
+    match mode() {
+        Mode::X2Apic => Msr::new(X2APIC_MSR_BASE + offset / 16).write(u64::from(value)),
+        Mode::XApic => {
+            let base = LAPIC_BASE.load(Ordering::Relaxed);
+            ptr::write_volatile((base + u64::from(offset)) as *mut u32, value);
+        }
+        Mode::Pic => {}
+    }
+}
+
+/// Masks every line of both 8259 PICs.
+///
+/// The PICs stay remapped to vectors 32-47, so a spurious interrupt from them still does not look like
+/// a CPU exception.
+unsafe fn disable_pics() {
+    Port::<u8>::new(0x21).write(0xff); // primary PIC data port
+    Port::<u8>::new(0xa1).write(0xff); // secondary PIC data port
+}
+
+struct IoApic {
+    base: VirtAddr,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            // IOREGSEL selects the register, IOWIN at offset 0x10 holds its value
+            ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+            ptr::read_volatile((self.base + 0x10u64).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+            ptr::write_volatile((self.base + 0x10u64).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn redirection_entries(&self) -> u32 {
+        ((self.read(IO_APIC_VERSION) >> 16) & 0xff) + 1
+    }
+
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let register = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        self.write(register, entry as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+    }
+
+    fn mask_all(&self) {
+        for gsi in 0..self.redirection_entries() {
+            self.write_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+
+    /// Builds a redirection entry that delivers its input as `vector` to the local APIC with id
+    /// `destination`.
+    ///
+    /// `flags` are the MPS INTI flags of the MADT (polarity in bits 0-1, trigger mode in bits 2-3),
+    /// 0 means the ISA default of edge triggered, active high.
+    fn redirection_entry(vector: u8, flags: u16, destination: u8) -> u64 {
+        let mut entry = u64::from(vector) | (u64::from(destination) << 56); // fixed, physical mode
+        if flags & 0b11 == 0b11 {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if (flags >> 2) & 0b11 == 0b11 {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        entry
+    }
+}
+
+/// The parts of the ACPI MADT ("APIC" table) we need.
+#[derive(Debug, Clone, Copy)]
+struct Madt {
+    io_apic_address: u64,
+    overrides: [Option<(u8, u32, u16)>; 16], // ---> ISA IRQ -> (IRQ, GSI, flags)
+}
+
+impl Madt {
+    /// Returns the GSI and flags the ISA `irq` is wired to.
+    fn isa_override(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|(source, _, _)| *source == irq)
+            .map_or((u32::from(irq), 0), |&(_, gsi, flags)| (gsi, flags))
+    }
+
+    /// Finds and parses the MADT through the RSDP in the BIOS memory area.
+    fn find() -> Option<Madt> {
+        let rsdp = find_rsdp()?;
+        let revision = unsafe { read::<u8>(rsdp + 15) };
+        let (table, entry_size) = if revision >= 2 {
+            (unsafe { read::<u64>(rsdp + 24) }, 8) // XSDT
+        } else {
+            (u64::from(unsafe { read::<u32>(rsdp + 16) }), 4) // RSDT
+        };
+
+        let table_length = u64::from(unsafe { read::<u32>(table + 4) });
+        let entries = (table_length.saturating_sub(36)) / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = table + 36 + i * entry_size;
+                match entry_size {
+                    8 => unsafe { read::<u64>(entry) },
+                    _ => u64::from(unsafe { read::<u32>(entry) }),
+                }
+            })
+            .find(|&header| unsafe { read::<[u8; 4]>(header) } == *b"APIC")
+            .map(|madt| unsafe { Self::parse(madt) })
+    }
+
+    unsafe fn parse(madt: u64) -> Madt {
+        let length = u64::from(read::<u32>(madt + 4));
+        let mut result = Madt {
+            io_apic_address: DEFAULT_IO_APIC_ADDRESS,
+            overrides: [None; 16],
+        };
+        let mut found_io_apic = false;
+        let mut override_count = 0;
+
+        // Entries follow the 36 byte header, the local APIC address and the flags
+        let mut entry = madt + 44;
+        while entry + 2 <= madt + length {
+            let entry_type = read::<u8>(entry);
+            let entry_length = u64::from(read::<u8>(entry + 1));
+            if entry_length < 2 {
+                break; // malformed table
+            }
+            match entry_type {
+                // I/O APIC, use the first one which handles the ISA IRQs
+                1 if !found_io_apic => {
+                    result.io_apic_address = u64::from(read::<u32>(entry + 4));
+                    found_io_apic = true;
+                }
+                // Interrupt source override
+                2 if override_count < result.overrides.len() => {
+                    let source = read::<u8>(entry + 3);
+                    let gsi = read::<u32>(entry + 4);
+                    let flags = read::<u16>(entry + 8);
+                    result.overrides[override_count] = Some((source, gsi, flags));
+                    override_count += 1;
+                }
+                _ => {}
+            }
+            entry += entry_length;
+        }
+        result
+    }
+}
+
+/// Reads a value from physical memory through the physical memory mapping.
+unsafe fn read<T: Copy>(phys: u64) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(phys)).as_ptr::<T>())
+}
+
+/// Searches the RSDP ("Root System Description Pointer") in the first KiB of the EBDA and in the BIOS
+/// area `0xe0000..0x100000`, it is always 16 byte aligned.
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(unsafe { read::<u16>(0x40e) }) << 4;
+    let candidates = (ebda..ebda + 1024).step_by(16).chain((0xe_0000..0x10_0000).step_by(16));
+    for addr in candidates {
+        if unsafe { read::<[u8; 8]>(addr) } == *b"RSD PTR " {
+            // The bytes of the ACPI 1.0 part must sum up to zero
+            let checksum = (0..20).fold(0u8, |sum, i| sum.wrapping_add(unsafe { read::<u8>(addr + i) }));
+            if checksum == 0 {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,307 @@
+// CPU Exceptions:
+// Every exception without a handler escalates to a double fault, which hides what actually went wrong.
+// So every architectural exception gets a handler here. Like the timer interrupt, they enter through
+// assembly stubs: every stub pushes its vector number (and a zero for exceptions without an error code)
+// and jumps to a common path that saves all general purpose registers. The Rust handler thus sees the
+// complete register file of the faulting code in an `ExceptionContext`.
+
+// Vector 9 (coprocessor segment overrun) is never raised by 64-bit CPUs, so it has no handler.
+
+use crate::thread::switch::{pop_registers, push_registers};
+use crate::{backtrace::Backtrace, gdt, hlt_loop, memory, serial, vga_buffer};
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// Register state of the interrupted code, layout must match `exception_common` below.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,     // ---> pushed by the stub
+    pub error_code: u64, // ---> pushed by the CPU, or 0 by the stub
+    // pushed by the CPU on exception entry
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+macro_rules! exception_stub {
+    ($vector:literal) => {
+        concat!(
+            ".global exception_", $vector, "\n",
+            "exception_", $vector, ":\n",
+            "push 0\n",
+            "push ", $vector, "\n",
+            "jmp exception_common\n",
+        )
+    };
+    ($vector:literal, error_code) => {
+        concat!(
+            ".global exception_", $vector, "\n",
+            "exception_", $vector, ":\n",
+            "push ", $vector, "\n",
+            "jmp exception_common\n",
+        )
+    };
+}
+
+// CPU frame (5 qwords), error code and vector plus 15 registers leave `rsp` 16 byte aligned for `call`
+global_asm!(
+    "exception_common:",
+    push_registers!(),
+    "cld",
+    "mov rdi, rsp",
+    "call {handler}",
+    pop_registers!(),
+    "add rsp, 16", // vector and error code
+    "iretq",
+    exception_stub!(0),
+    exception_stub!(1),
+    exception_stub!(2),
+    exception_stub!(3),
+    exception_stub!(4),
+    exception_stub!(5),
+    exception_stub!(6),
+    exception_stub!(7),
+    exception_stub!(8, error_code),
+    exception_stub!(10, error_code),
+    exception_stub!(11, error_code),
+    exception_stub!(12, error_code),
+    exception_stub!(13, error_code),
+    exception_stub!(14, error_code),
+    exception_stub!(16),
+    exception_stub!(17, error_code),
+    exception_stub!(18),
+    exception_stub!(19),
+    exception_stub!(20),
+    exception_stub!(21, error_code),
+    exception_stub!(28),
+    exception_stub!(29, error_code),
+    exception_stub!(30, error_code),
+    handler = sym exception_handler,
+);
+
+extern "C" {
+    fn exception_0();
+    fn exception_1();
+    fn exception_2();
+    fn exception_3();
+    fn exception_4();
+    fn exception_5();
+    fn exception_6();
+    fn exception_7();
+    fn exception_8();
+    fn exception_10();
+    fn exception_11();
+    fn exception_12();
+    fn exception_13();
+    fn exception_14();
+    fn exception_16();
+    fn exception_17();
+    fn exception_18();
+    fn exception_19();
+    fn exception_20();
+    fn exception_21();
+    fn exception_28();
+    fn exception_29();
+    fn exception_30();
+}
+
+fn addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Points every exception entry of `idt` to its stub.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(exception_0));
+        idt.debug.set_handler_addr(addr(exception_1));
+        idt.non_maskable_interrupt.set_handler_addr(addr(exception_2));
+        idt.breakpoint.set_handler_addr(addr(exception_3));
+        idt.overflow.set_handler_addr(addr(exception_4));
+        idt.bound_range_exceeded.set_handler_addr(addr(exception_5));
+        idt.invalid_opcode.set_handler_addr(addr(exception_6));
+        idt.device_not_available.set_handler_addr(addr(exception_7));
+        idt.double_fault
+            .set_handler_addr(addr(exception_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // Switch stack on double fault
+        idt.invalid_tss.set_handler_addr(addr(exception_10));
+        idt.segment_not_present.set_handler_addr(addr(exception_11));
+        idt.stack_segment_fault.set_handler_addr(addr(exception_12));
+        idt.general_protection_fault.set_handler_addr(addr(exception_13));
+        idt.page_fault
+            .set_handler_addr(addr(exception_14))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_addr(addr(exception_16));
+        idt.alignment_check.set_handler_addr(addr(exception_17));
+        idt.machine_check.set_handler_addr(addr(exception_18));
+        idt.simd_floating_point.set_handler_addr(addr(exception_19));
+        idt.virtualization.set_handler_addr(addr(exception_20));
+        idt.cp_protection_exception.set_handler_addr(addr(exception_21));
+        idt.hv_injection_exception.set_handler_addr(addr(exception_28));
+        idt.vmm_communication_exception.set_handler_addr(addr(exception_29));
+        idt.security_exception.set_handler_addr(addr(exception_30));
+    }
+}
+
+/// Mnemonic and name of an exception vector.
+pub fn exception_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "DIVIDE ERROR"),
+        1 => ("#DB", "DEBUG"),
+        2 => ("NMI", "NON-MASKABLE INTERRUPT"),
+        3 => ("#BP", "BREAKPOINT"),
+        4 => ("#OF", "OVERFLOW"),
+        5 => ("#BR", "BOUND RANGE EXCEEDED"),
+        6 => ("#UD", "INVALID OPCODE"),
+        7 => ("#NM", "DEVICE NOT AVAILABLE"),
+        8 => ("#DF", "DOUBLE FAULT"),
+        10 => ("#TS", "INVALID TSS"),
+        11 => ("#NP", "SEGMENT NOT PRESENT"),
+        12 => ("#SS", "STACK SEGMENT FAULT"),
+        13 => ("#GP", "GENERAL PROTECTION FAULT"),
+        14 => ("#PF", "PAGE FAULT"),
+        16 => ("#MF", "X87 FLOATING POINT"),
+        17 => ("#AC", "ALIGNMENT CHECK"),
+        18 => ("#MC", "MACHINE CHECK"),
+        19 => ("#XM", "SIMD FLOATING POINT"),
+        20 => ("#VE", "VIRTUALIZATION"),
+        21 => ("#CP", "CONTROL PROTECTION"),
+        28 => ("#HV", "HYPERVISOR INJECTION"),
+        29 => ("#VC", "VMM COMMUNICATION"),
+        30 => ("#SX", "SECURITY"),
+        _ => ("??", "UNKNOWN"),
+    }
+}
+
+/// Common Rust entry of all exception stubs. Returning resumes the interrupted code.
+extern "C" fn exception_handler(context: &mut ExceptionContext) {
+    match context.vector {
+        3 => {
+            // int3 is used on purpose, just report it and continue
+            let mut out = CrashWriter;
+            let _ = writeln!(out, "[EXCEPTION] BREAKPOINT at {:#x}", context.rip);
+        }
+        14 => page_fault(context),
+        vector => {
+            crash_report(context);
+            panic!("[EXCEPTION] {}", exception_name(vector).1);
+        }
+    }
+}
+
+fn page_fault(context: &ExceptionContext) {
+    use x86_64::registers::control::Cr2;
+
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+    let accessed_address = Cr2::read(); // Cr2 Register containes address that caused Fault
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // Non-present page below a guarded stack
+        if let Some(stack) = memory::stack::stack_overflow_index(accessed_address) {
+            crash_report(context);
+            panic!("[EXCEPTION] PAGE FAULT: stack overflow in stack {}", stack);
+        }
+    }
+
+    crash_report(context);
+    hlt_loop(); // Continue only after resolving page Fault
+}
+
+/// Prints everything we know about the exception to the screen and the serial port.
+pub fn crash_report(context: &ExceptionContext) {
+    use x86_64::registers::control::{Cr2, Cr3};
+
+    let (mnemonic, name) = exception_name(context.vector);
+    let mut out = CrashWriter;
+    let _ = writeln!(out, "[EXCEPTION] {} {} (vector {})", mnemonic, name, context.vector);
+    let _ = writeln!(out, "Error Code: {}", DecodedErrorCode(context.vector, context.error_code));
+    let _ = writeln!(out, "RIP {:#018x}  RSP {:#018x}", context.rip, context.rsp);
+    let _ = writeln!(out, "CR2 {:#018x}  CR3 {:#018x}", Cr2::read().as_u64(), Cr3::read().0.start_address().as_u64());
+    let _ = writeln!(out, "RFLAGS {:#x}  CS {:#x}  SS {:#x}", context.rflags, context.cs, context.ss);
+    let registers = [
+        ("RAX", context.rax), ("RBX", context.rbx), ("RCX", context.rcx), ("RDX", context.rdx),
+        ("RSI", context.rsi), ("RDI", context.rdi), ("RBP", context.rbp), ("R8 ", context.r8),
+        ("R9 ", context.r9), ("R10", context.r10), ("R11", context.r11), ("R12", context.r12),
+        ("R13", context.r13), ("R14", context.r14), ("R15", context.r15),
+    ];
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            let _ = write!(out, "{} {:#018x}  ", name, value);
+        }
+        let _ = writeln!(out);
+    }
+    let backtrace = Backtrace {
+        rip: context.rip,
+        rbp: context.rbp,
+    };
+    let _ = writeln!(out, "{}", backtrace);
+}
+
+/// Error code formatted according to the exception that pushed it.
+struct DecodedErrorCode(u64, u64);
+
+impl fmt::Display for DecodedErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let DecodedErrorCode(vector, code) = *self;
+        match vector {
+            // Selector error code: bit 0 external event, bits 1-2 descriptor table, bits 3-15 index
+            10 | 11 | 12 | 13 => {
+                if code == 0 {
+                    return write!(f, "0 (not segment related)");
+                }
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "{:#x} (selector index {} in {}{})",
+                    code,
+                    (code >> 3) & 0x1fff,
+                    table,
+                    if code & 1 != 0 { ", external" } else { "" }
+                )
+            }
+            14 => write!(f, "{:#x} ({:?})", code, PageFaultErrorCode::from_bits_truncate(code)),
+            8 | 17 | 21 | 29 | 30 => write!(f, "{:#x}", code),
+            _ => write!(f, "none"),
+        }
+    }
+}
+
+/// Writes to both the VGA buffer and the serial port.
+///
+/// The exception might have interrupted code that holds one of their locks, in which case that output
+/// is skipped instead of deadlocking.
+struct CrashWriter;
+
+impl Write for CrashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut writer) = vga_buffer::WRITER.try_lock() {
+            writer.write_string(s);
+        }
+        if let Some(mut serial) = serial::SERIAL1.try_lock() {
+            let _ = serial.write_str(s);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// IRQ Handler Registration:
+// Instead of a fixed IDT entry per device, every one of the 16 legacy IRQ lines enters through the same
+// dispatch path, which calls all handlers registered for that line and then sends the EOI. Drivers can
+// thus attach to a line and detach from it at runtime, and several devices can share one line (each
+// handler has to check whether its device actually raised the interrupt).
+
+// The timer line is special because its handler might switch threads: it enters through
+// `thread::switch`, but still runs the handlers registered for line 0 before scheduling.
+
+use super::{apic, end_of_interrupt, PIC_1_OFFSET};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+/// Number of legacy IRQ lines.
+pub const IRQ_LINES: u8 = 16;
+/// Maximum number of handlers sharing one line.
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+/// Called with the IRQ line that fired, in interrupt context with interrupts disabled.
+///
+/// Must not block or allocate, the EOI is sent after all handlers of the line ran.
+pub type IrqHandler = fn(line: u8);
+
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES as usize]);
+static ENABLED_LINES: AtomicU16 = AtomicU16::new(1); // ---> Bit per unmasked line, the timer always is
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a registered handler, pass it to `unregister` to detach the handler.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrqHandle {
+    line: u8,
+    slot: usize,
+}
+
+impl IrqHandle {
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine, // ---> Line is not in 0..16
+    LineFull,    // ---> Line already has `MAX_HANDLERS_PER_LINE` handlers
+}
+
+/// Attaches `handler` to IRQ `line` and unmasks the line.
+pub fn register(line: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    if line >= IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    // Interrupts disabled so the dispatch path never finds the table locked
+    interrupts::without_interrupts(|| -> Result<IrqHandle, IrqError> {
+        let mut handlers = HANDLERS.lock();
+        let slots = &mut handlers[usize::from(line)];
+        let slot = slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::LineFull)?;
+        slots[slot] = Some(handler);
+        set_line_enabled(line, true);
+        Ok(IrqHandle { line, slot })
+    })
+}
+
+/// Detaches a handler, the line is masked again when its last handler is gone.
+pub fn unregister(handle: IrqHandle) {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slots = &mut handlers[usize::from(handle.line)];
+        slots[handle.slot] = None;
+        if handle.line != 0 && slots.iter().all(Option::is_none) {
+            set_line_enabled(handle.line, false);
+        }
+    });
+}
+
+/// Number of spurious interrupts seen, from the PICs (IRQ 7 and 15) or the local APIC.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Returns whether `line` is unmasked because handlers are registered for it.
+pub fn is_line_enabled(line: u8) -> bool {
+    ENABLED_LINES.load(Ordering::Relaxed) & (1 << line) != 0
+}
+
+fn set_line_enabled(line: u8, enabled: bool) {
+    if enabled {
+        ENABLED_LINES.fetch_or(1 << line, Ordering::Relaxed);
+    } else {
+        ENABLED_LINES.fetch_and(!(1 << line), Ordering::Relaxed);
+    }
+
+    match apic::mode() {
+        apic::Mode::Pic => unsafe { set_pic_line_masked(line, !enabled) },
+        apic::Mode::XApic | apic::Mode::X2Apic => apic::set_isa_irq_masked(line, !enabled),
+    }
+}
+
+/// Sets the mask bit of `line` in the interrupt mask register of its PIC.
+unsafe fn set_pic_line_masked(line: u8, masked: bool) {
+    let (mut data, bit) = if line < 8 {
+        (Port::<u8>::new(0x21), line)
+    } else {
+        // lines of the secondary PIC arrive through line 2 of the primary one
+        if !masked {
+            let mut primary = Port::<u8>::new(0x21);
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+        }
+        (Port::<u8>::new(0xa1), line - 8)
+    };
+    let mask = data.read();
+    data.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+}
+
+/// Calls every handler registered for `line`, without sending EOI.
+pub(super) fn run_handlers(line: u8) {
+    // Copy the handlers out so a handler may (un)register without deadlocking
+    let handlers = HANDLERS.lock()[usize::from(line)];
+    for handler in handlers.iter().flatten() {
+        handler(line);
+    }
+}
+
+/// Common path of all IRQ lines except the timer.
+fn dispatch(line: u8) {
+    if apic::mode() == apic::Mode::Pic && is_spurious(line) {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        if line == 15 {
+            // The primary PIC did see a real interrupt on its cascade line and expects an EOI
+            unsafe { Port::<u8>::new(0x20).write(0x20) };
+        }
+        return;
+    }
+    run_handlers(line);
+    end_of_interrupt(PIC_1_OFFSET + line);
+}
+
+/// The PICs raise IRQ 7 (or 15) when an interrupt disappears before it is acknowledged. In that case
+/// the line is not set in the In-Service Register and must not receive an EOI.
+fn is_spurious(line: u8) -> bool {
+    let command_port = match line {
+        7 => 0x20,
+        15 => 0xa0,
+        _ => return false,
+    };
+    let mut command = Port::<u8>::new(command_port);
+    unsafe {
+        command.write(0x0b); // OCW3: next read of the command port returns the ISR
+        command.read() & 0x80 == 0
+    }
+}
+
+/// Called by the local APIC spurious interrupt handler.
+pub(super) fn count_spurious() {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
+macro_rules! irq_entries {
+    ($($line:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn entry(_stack_frame: InterruptStackFrame) {
+                dispatch($line);
+            }
+            entry as HandlerFunc
+        }),*]
+    };
+}
+
+/// Installs the common entry of every IRQ line except the timer in `idt`.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    let entries: [HandlerFunc; IRQ_LINES as usize] =
+        irq_entries!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    // line 0 is the timer, which enters through `thread::switch`
+    for (line, &entry) in entries.iter().enumerate().skip(1) {
+        idt[usize::from(PIC_1_OFFSET) + line].set_handler_fn(entry);
+    }
+}
+
+#[test_case]
+fn test_register_limits() {
+    fn handler(_line: u8) {}
+
+    assert_eq!(register(IRQ_LINES, handler), Err(IrqError::InvalidLine));
+    let handles: [IrqHandle; MAX_HANDLERS_PER_LINE] =
+        core::array::from_fn(|_| register(5, handler).expect("register failed"));
+    assert_eq!(register(5, handler), Err(IrqError::LineFull));
+    for handle in handles {
+        unregister(handle);
+    }
+    assert!(!is_line_enabled(5));
+}
+
+#[test_case]
+fn test_shared_line_dispatch() {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    fn first(_line: u8) {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+    }
+    fn second(_line: u8) {
+        CALLS.fetch_add(10, Ordering::SeqCst);
+    }
+
+    let first = register(3, first).unwrap();
+    let second = register(3, second).unwrap();
+    // Raise the vector of line 3 in software, it takes the same path as the hardware interrupt
+    unsafe { core::arch::asm!("int 35") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 11);
+
+    unregister(first);
+    unsafe { core::arch::asm!("int 35") };
+    assert_eq!(CALLS.load(Ordering::SeqCst), 21);
+    unregister(second);
+}
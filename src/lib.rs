@@ -9,14 +9,23 @@
 extern crate alloc;
 
 pub mod allocator;
+pub mod backtrace;
+pub mod fw_cfg;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
+pub mod task;
+pub mod testing;
+pub mod thread;
+pub mod time;
 pub mod vga_buffer;
 
+#[cfg(test)]
 use core::panic::PanicInfo;
 
+pub use testing::{test_panic_handler, test_runner, Testable};
+
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
@@ -24,6 +33,7 @@ pub fn init() {
         // Intialize PICs could cause undefined behaviour if PIC is misconfigured
         interrupts::PICS.lock().initialize();
     };
+    time::init_pit(time::TIMER_FREQUENCY_HZ); // Timer interrupt frequency instead of the ~18.2 Hz default
     x86_64::instructions::interrupts::enable(); // Enable External Interrupts
 }
 
@@ -41,6 +51,9 @@ pub enum QemuExitCode {
     // Exit with exit code different from QEMU default code to differ test
     Success = 0x10, // 33 after exit
     Failed = 0x11,  // 35 after exit
+    OutOfMemory = 0x12, // 37 after exit, see `allocator::oom`
+    TestsFailed = 0x13, // 39 after exit, every test ran but some failed, see `testing`
+    TimedOut = 0x14, // 41 after exit, a test ran past its budget, see `testing::watchdog`
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
@@ -53,33 +66,6 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T: Fn()> Testable for T {
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>()); // Function name
-        self();
-        serial_println!("[ok]");
-    }
-}
-
-pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
-    }
-    exit_qemu(QemuExitCode::Success);
-}
-
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
-}
-
 #[test_case]
 fn trivial_assertion() {
     assert_eq!(1, 1);
@@ -22,7 +22,8 @@ entry_point!(kernel_main);
 // `physical_memory_offset` (Adding this offset to physical address, we get Virtual Address)
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use enigma::allocator;
-    use enigma::memory::{self, BootInfoFrameAllocator};
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+    use enigma::task::{executor::Executor, keyboard, Task};
     use x86_64::VirtAddr;
 
     println!("Hello World{}", "!!");
@@ -30,8 +31,14 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator); // Page table is now shared, e.g. for thread stacks
+    match enigma::interrupts::apic::init() {
+        Ok(mode) => println!("Interrupt controller: {:?}", mode),
+        Err(err) => println!("No APIC ({:?}), staying on 8259 PIC", err),
+    }
+    enigma::thread::init(); // From now on the timer preempts kernel_main like any other thread
 
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
@@ -60,7 +67,20 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("It did not crash!");
-    enigma::hlt_loop(); // Instead of endless loop, halt till next interrupt
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.run(); // Polls tasks and halts till next interrupt when none is ready
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 /// This function is called on Panic
@@ -68,6 +88,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", enigma::backtrace::Backtrace::here());
     loop {}
 }
 
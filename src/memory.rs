@@ -1,8 +1,20 @@
+pub mod buddy;
+pub mod stack;
+
+use buddy::BuddyFrameAllocator;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    instructions::interrupts,
+    structures::paging::{
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
+// Virtual address at which the bootloader mapped the complete physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -10,6 +22,7 @@ use x86_64::{
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
     let level4_table = active_level_4_table(phys_mem_offset);
     OffsetPageTable::new(level4_table, phys_mem_offset)
 }
@@ -33,46 +46,120 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+/// The kernel page table together with the frame allocator used to create new mappings in it.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// Hands the page table and frame allocator over to the kernel, so that memory can be mapped after boot
+/// (e.g. for thread stacks).
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BuddyFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory {
+            mapper,
+            frame_allocator,
+        });
+    });
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked as
-    /// `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
+/// Runs `f` with exclusive access to the kernel page table and frame allocator.
+///
+/// Returns `None` if `install` was not called yet. Interrupts are disabled while `f` runs, so no other
+/// thread can be scheduled while the lock is held.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
+/// Returns the virtual address of `phys` inside the bootloader's mapping of physical memory.
+///
+/// Only valid after `init` was called.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // Get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        // Map each regions to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-
-        // Transform to an iterator of frame start addresses
-        let frame_addrs = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addrs.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Returns whether every byte of `start..start + size` is mapped in the active page table.
+///
+/// Walks the page table directly instead of taking the `KERNEL_MEMORY` lock, so it can be used from
+/// the allocator and from exception handlers. Returns `false` before `init` was called.
+pub fn is_mapped(start: VirtAddr, size: u64) -> bool {
+    if size == 0 {
+        return true;
     }
+    let end = match start.as_u64().checked_add(size - 1) {
+        Some(end) => end,
+        None => return false,
+    };
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(VirtAddr::new_truncate(end)),
+    );
+    pages.into_iter().all(|page| is_page_mapped(page.start_address()))
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+fn is_page_mapped(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    if PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let (level_4_frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_frame = level_4_frame.start_address();
+    for (level, &index) in indexes.iter().enumerate() {
+        let table: &PageTable = unsafe { &*phys_to_virt(table_frame).as_ptr() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // the P4 table can't hold huge pages, the last level always points to a page
+        if level == 3 || (level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            return true;
+        }
+        table_frame = entry.addr();
     }
+    unreachable!()
+}
+
+/// Makes the memory-mapped device registers at `phys..phys + size` accessible and returns their
+/// virtual address.
+///
+/// The bootloader only maps physical memory up to the end of the memory map, so device memory above it
+/// (like the APIC registers) might not be mapped yet. Missing pages are mapped as uncacheable at the same
+/// place in the physical memory mapping. Returns `None` if the kernel memory is not installed or the
+/// mapping failed.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Option<VirtAddr> {
+    let virt = phys_to_virt(phys);
+    let page_range = {
+        let start_page: Page<Size4KiB> = Page::containing_address(virt);
+        let end_page = Page::containing_address(virt + size - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    with_kernel_memory(|memory| {
+        for page in page_range {
+            if memory.mapper.translate_addr(page.start_address()).is_some() {
+                continue; // already part of the physical memory mapping
+            }
+            let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+            let frame = PhysFrame::containing_address(PhysAddr::new(
+                page.start_address().as_u64() - offset,
+            ));
+            let flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::WRITE_THROUGH;
+            unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)
+                    .ok()?
+                    .flush();
+            }
+        }
+        Some(virt)
+    })
+    .flatten()
 }
@@ -0,0 +1,273 @@
+// Buddy Frame Allocator:
+// Physical memory is managed in blocks of 2^order contiguous frames, from a single 4 KiB frame (order 0)
+// up to 4 MiB (order 10), every block aligned to its own size. A block of order n + 1 splits into two
+// buddies of order n, and the buddy of a block is found by flipping one bit of its frame number. This
+// makes large, aligned allocations like 2 MiB huge pages cheap, and freed blocks are merged with their
+// buddies again so that such allocations keep working after the memory was used for single frames.
+
+// The free lists are doubly linked through the free frames themselves (accessed through the physical
+// memory mapping), so a buddy can be unlinked in O(1) when it is merged. To know whether a buddy is free
+// without walking a list, one byte per frame records the order of the free block starting there.
+
+// Memory is split into zones, like Linux does: devices that can only address 24 (ISA DMA) or 32 bits
+// need frames below 16 MiB or 4 GiB. Allocations prefer the highest zone, so the low zones stay
+// available for such devices.
+
+use super::phys_to_virt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr,
+};
+
+/// Largest order, blocks of this order are 4 MiB.
+pub const MAX_ORDER: usize = 10;
+/// Order of a 2 MiB block.
+pub const HUGE_PAGE_ORDER: usize = 9;
+const ORDERS: usize = MAX_ORDER + 1;
+
+const FRAME_SIZE: u64 = 4096;
+const NONE: usize = usize::MAX; // ---> End of a free list
+
+/// A range of physical memory that free blocks never cross.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Zone {
+    Dma,   // ---> Below 16 MiB, reachable by ISA DMA
+    Dma32, // ---> Below 4 GiB, reachable by 32 bit devices
+    Normal,
+}
+
+impl Zone {
+    const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// Zone that contains the given frame number.
+    fn of(frame: usize) -> Zone {
+        // Both limits are multiples of the largest block, so a block is always inside one zone
+        match frame as u64 * FRAME_SIZE {
+            addr if addr < 16 * 1024 * 1024 => Zone::Dma,
+            addr if addr < 4 * 1024 * 1024 * 1024 => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+}
+
+/// Links of a free list, stored in the first frame of every free block.
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+/// The free blocks of one zone.
+struct FreeArea {
+    heads: [usize; ORDERS], // ---> First free block of every order
+    free_frames: usize,
+}
+
+/// A FrameAllocator that hands out naturally aligned blocks of 2^order frames from the bootloader's
+/// memory map.
+pub struct BuddyFrameAllocator {
+    free_orders: &'static mut [u8], // ---> Per frame: order + 1 if a free block starts there, else 0
+    areas: [FreeArea; 3],            // ---> Indexed by `Zone`
+    usable_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Create a BuddyFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked as
+    /// `USABLE` in it are really unused. `memory::init` must have been called before, because the
+    /// free lists are written through the physical memory mapping.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let table_frames = (frame_count + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+
+        // The order table goes to the start of the first usable region large enough to hold it
+        let table_region = usable_regions()
+            .find(|r| {
+                (r.range.end_frame_number - r.range.start_frame_number) as usize >= table_frames
+            })
+            .expect("no usable region large enough for the frame order table");
+        let table_start = table_region.range.start_frame_number as usize;
+        let table_ptr = phys_to_virt(PhysAddr::new(table_region.range.start_addr())).as_mut_ptr();
+        let free_orders = core::slice::from_raw_parts_mut(table_ptr, frame_count);
+        free_orders.fill(0);
+
+        const EMPTY: FreeArea = FreeArea {
+            heads: [NONE; ORDERS],
+            free_frames: 0,
+        };
+        let mut allocator = BuddyFrameAllocator {
+            free_orders,
+            areas: [EMPTY; 3],
+            usable_frames: 0,
+        };
+        for region in usable_regions() {
+            let mut start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            if start == table_start {
+                start += table_frames;
+            }
+            allocator.add_range(start, end);
+        }
+        allocator.usable_frames = allocator.free_frames() + table_frames;
+        allocator
+    }
+
+    /// Cuts the frames `start..end` into the largest aligned blocks that fit and frees them.
+    unsafe fn add_range(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            // Freeing merges the block with free buddies, e.g. of an adjacent region
+            self.free(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Allocates a block of `2^order` frames, aligned to its size, from the highest zone that has one.
+    pub fn allocate_block(&mut self, order: usize) -> Option<PhysFrame> {
+        Zone::ALL
+            .iter()
+            .rev()
+            .find_map(|&zone| self.allocate_block_in(zone, order))
+    }
+
+    /// Allocates a block of `2^order` frames, aligned to its size, from the given zone.
+    pub fn allocate_block_in(&mut self, zone: Zone, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // smallest order that has a free block
+        let found = (order..ORDERS).find(|&o| self.areas[zone as usize].heads[o] != NONE)?;
+        let frame = self.areas[zone as usize].heads[found];
+        unsafe {
+            self.remove(frame, found);
+            // split it down, the upper halves become free blocks of the lower orders
+            for split in (order..found).rev() {
+                self.push(frame + (1 << split), split);
+            }
+        }
+        Some(frame_from_number(frame))
+    }
+
+    /// Frees a block returned by `allocate_block` for the same `order`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames are not used anymore.
+    pub unsafe fn deallocate_block(&mut self, first: PhysFrame, order: usize) {
+        let frame = (first.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(order <= MAX_ORDER && frame % (1 << order) == 0, "invalid block {:?}", first);
+        assert_eq!(self.free_orders[frame], 0, "double free of frame {:?}", first);
+        self.free(frame, order);
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.areas.iter().map(|area| area.free_frames).sum()
+    }
+
+    /// Number of frames that can still be allocated from the given zone.
+    pub fn zone_free_frames(&self, zone: Zone) -> usize {
+        self.areas[zone as usize].free_frames
+    }
+
+    /// Number of usable frames that are allocated, including the frames holding the order table.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames()
+    }
+
+    /// Puts the block back to its free list, merging it with its buddy as long as that is free too.
+    unsafe fn free(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if self.free_orders.get(buddy) != Some(&(order as u8 + 1)) {
+                break;
+            }
+            self.remove(buddy, order);
+            // the merged block starts at the lower of the two buddies
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Adds the block starting at `frame` to the front of the free list of `order`.
+    unsafe fn push(&mut self, frame: usize, order: usize) {
+        let area = &mut self.areas[Zone::of(frame) as usize];
+        let head = area.heads[order];
+        *free_block(frame) = FreeBlock {
+            next: head,
+            prev: NONE,
+        };
+        if head != NONE {
+            free_block(head).prev = frame;
+        }
+        area.heads[order] = frame;
+        area.free_frames += 1 << order;
+        self.free_orders[frame] = order as u8 + 1;
+    }
+
+    /// Unlinks the free block starting at `frame` from the free list of `order`.
+    unsafe fn remove(&mut self, frame: usize, order: usize) {
+        let area = &mut self.areas[Zone::of(frame) as usize];
+        let FreeBlock { next, prev } = *free_block(frame);
+        if prev == NONE {
+            area.heads[order] = next;
+        } else {
+            free_block(prev).next = next;
+        }
+        if next != NONE {
+            free_block(next).prev = prev;
+        }
+        area.free_frames -= 1 << order;
+        self.free_orders[frame] = 0;
+    }
+}
+
+/// The free list links stored in the given (free) frame.
+unsafe fn free_block(frame: usize) -> &'static mut FreeBlock {
+    &mut *phys_to_virt(PhysAddr::new(frame as u64 * FRAME_SIZE)).as_mut_ptr()
+}
+
+fn frame_from_number(number: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number as u64 * FRAME_SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_block(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let block = self.allocate_block(HUGE_PAGE_ORDER)?;
+        // blocks are aligned to their size
+        Some(PhysFrame::from_start_address(block.start_address()).unwrap())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate_block(frame, 0);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_block(first, HUGE_PAGE_ORDER);
+    }
+}
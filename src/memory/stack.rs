@@ -0,0 +1,136 @@
+// Guarded Stacks:
+// A stack that grows past its end silently overwrites whatever lies below it. To catch that, every stack
+// gets its own slot in a dedicated virtual address range, and only the top pages of the slot are mapped.
+// The pages below the stack stay unmapped, so the first access past the end of the stack causes a page
+// fault, and because slots have a fixed size the faulting address tells us which stack overflowed.
+
+use super::with_kernel_memory;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Start of the virtual address range reserved for stacks.
+pub const STACKS_START: u64 = 0x_5555_5555_0000;
+/// Size of the virtual range reserved for every stack, including its guard pages.
+pub const STACK_SLOT_SIZE: u64 = 64 * 1024; // 64 KiB
+/// Number of slots in the stack range.
+pub const MAX_STACKS: u64 = 4096;
+/// Largest stack that still leaves one unmapped guard page at the bottom of its slot.
+pub const MAX_STACK_PAGES: u64 = STACK_SLOT_SIZE / 4096 - 1;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0); // ---> First slot that was never handed out
+static FREE_STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new()); // ---> Mapped stacks ready for reuse
+
+/// A mapped stack with unmapped guard pages below it.
+///
+/// Dropping it keeps the pages mapped and makes the stack available for reuse by `alloc_stack`.
+#[derive(Debug)]
+pub struct Stack {
+    index: u64,
+    pages: u64,
+}
+
+impl Stack {
+    /// Number of the stack, as reported on overflow.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Highest address of the stack, stacks on x86 grow downwards.
+    pub fn top(&self) -> VirtAddr {
+        slot_start(self.index) + STACK_SLOT_SIZE
+    }
+
+    /// Lowest mapped address of the stack, the page below it is the guard page.
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * 4096
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let stack = Stack {
+            index: self.index,
+            pages: self.pages,
+        };
+        // Pushing can allocate, an interrupt must not get to the heap or this lock in the meantime
+        interrupts::without_interrupts(|| FREE_STACKS.lock().push(stack));
+    }
+}
+
+fn slot_start(index: u64) -> VirtAddr {
+    VirtAddr::new(STACKS_START + index * STACK_SLOT_SIZE)
+}
+
+/// Allocates a stack of `pages` pages (at most `MAX_STACK_PAGES`) with a guard page below it.
+///
+/// Needs the kernel page table, see `memory::install`.
+pub fn alloc_stack(pages: u64) -> Result<Stack, MapToError<Size4KiB>> {
+    assert!(pages > 0 && pages <= MAX_STACK_PAGES, "invalid stack size");
+
+    // Reuse a freed stack that is large enough
+    let reused = interrupts::without_interrupts(|| {
+        let mut free_stacks = FREE_STACKS.lock();
+        let position = free_stacks.iter().position(|s| s.pages >= pages)?;
+        Some(free_stacks.swap_remove(position))
+    });
+    if let Some(stack) = reused {
+        return Ok(stack);
+    }
+
+    let index = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_STACKS {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    let stack = Stack { index, pages };
+
+    let page_range = {
+        let start_page = Page::containing_address(stack.bottom());
+        let end_page = Page::containing_address(stack.top() - 1u64);
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    let result = with_kernel_memory(|memory| -> Result<(), MapToError<Size4KiB>> {
+        for page in page_range {
+            let frame = FrameAllocator::<Size4KiB>::allocate_frame(&mut memory.frame_allocator)
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)?
+                    .flush();
+            }
+        }
+        Ok(())
+    })
+    .expect("kernel memory not installed");
+
+    if let Err(err) = result {
+        // The slot is only partially mapped, never hand it out again
+        core::mem::forget(stack);
+        return Err(err);
+    }
+    Ok(stack)
+}
+
+/// Returns the number of the stack whose slot contains `addr`.
+///
+/// Only the mapped part of a slot is ever accessed on purpose, so a page fault on a non-present page
+/// at such an address is a stack overflow. Does not lock, so it can be used from the page fault handler.
+pub fn stack_overflow_index(addr: VirtAddr) -> Option<u64> {
+    let addr = addr.as_u64();
+    let end = STACKS_START + NEXT_SLOT.load(Ordering::Relaxed).min(MAX_STACKS) * STACK_SLOT_SIZE;
+    if (STACKS_START..end).contains(&addr) {
+        Some((addr - STACKS_START) / STACK_SLOT_SIZE)
+    } else {
+        None
+    }
+}
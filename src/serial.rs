@@ -39,3 +39,19 @@ pub fn _print(args: ::core::fmt::Arguments) {
         SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
     })
 }
+
+/// Reads and discards every byte COM1 received, which acknowledges its "data available" interrupt.
+///
+/// Nothing consumes serial input yet.
+pub(crate) fn drain_input() {
+    use x86_64::instructions::port::Port;
+
+    let mut data: Port<u8> = Port::new(0x3F8);
+    let mut line_status: Port<u8> = Port::new(0x3F8 + 5);
+    unsafe {
+        while line_status.read() & 1 != 0 {
+            // bit 0 of line status register: data ready
+            data.read();
+        }
+    }
+}
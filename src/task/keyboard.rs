@@ -0,0 +1,166 @@
+// Keyboard input as an async stream:
+// The keyboard interrupt handler must return quickly and must never block, so it only reads the raw
+// scancode from the PS/2 data port and pushes it into `SCANCODE_QUEUE`. Decoding the scancodes into keys
+// happens in a normal task that awaits `ScancodeStream`, so any part of the kernel can consume keyboard
+// input instead of it being printed from interrupt context.
+
+use crate::{print, println};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+/// Number of scancodes that can wait in the queue before the interrupt handler starts dropping them.
+const SCANCODE_QUEUE_CAPACITY: usize = 128;
+
+// Statically initialized so the queue exists before interrupts are enabled and the interrupt handler
+// never has to allocate
+static SCANCODE_QUEUE: ScancodeQueue = ScancodeQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new(); // Waker of the task currently awaiting a scancode
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Fixed-capacity lock-free ring buffer with a single producer (the keyboard interrupt handler) and a
+/// single consumer (the `ScancodeStream`).
+struct ScancodeQueue {
+    buffer: [AtomicU8; SCANCODE_QUEUE_CAPACITY],
+    head: AtomicUsize, // ---> Total number of scancodes read, only written by the consumer
+    tail: AtomicUsize, // ---> Total number of scancodes written, only written by the producer
+}
+
+impl ScancodeQueue {
+    const fn new() -> Self {
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+        Self {
+            buffer: [EMPTY; SCANCODE_QUEUE_CAPACITY],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends the scancode, or returns it back as an error if the queue is full.
+    fn push(&self, scancode: u8) -> Result<(), u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        // Acquire pairs with the Release in `pop` so the slot is not overwritten before it was read
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == SCANCODE_QUEUE_CAPACITY {
+            return Err(scancode);
+        }
+        self.buffer[tail % SCANCODE_QUEUE_CAPACITY].store(scancode, Ordering::Relaxed);
+        // Release publishes the slot write before the consumer can see the new tail
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes the oldest scancode from the queue.
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let scancode = self.buffer[head % SCANCODE_QUEUE_CAPACITY].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(scancode)
+    }
+}
+
+/// Called by the keyboard interrupt handler
+///
+/// Must not block or allocate. If the queue is full the scancode is dropped and counted.
+pub(crate) fn add_scancode(scancode: u8) {
+    if SCANCODE_QUEUE.push(scancode).is_err() {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        WAKER.wake(); // Notify the task awaiting the stream, if any
+    }
+}
+
+/// Returns how many scancodes were dropped because the queue was full.
+pub fn dropped_scancodes() -> usize {
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
+}
+
+/// Stream of raw scancodes pushed by the keyboard interrupt handler.
+///
+/// The queue has a single consumer, so only one `ScancodeStream` may exist.
+pub struct ScancodeStream {
+    _private: (), // prevent construction from outside of the module
+}
+
+impl ScancodeStream {
+    // No `Default`: the interrupt handler feeds a single queue, so a second stream would panic here
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            panic!("ScancodeStream::new should only be called once");
+        }
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        // fast path, avoids registering the waker if a scancode is already there
+        if let Some(scancode) = SCANCODE_QUEUE.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+        // An interrupt might have pushed a scancode before the waker was registered, check again
+        match SCANCODE_QUEUE.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Decodes scancodes from the keyboard and prints the pressed keys to the screen.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    // Create Keyboard object with US keyboard layout and the scancode set 1
+    // PS/2 keyboard emulate scancode set 1 (IBM XT)
+    let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore);
+    let mut reported_drops = 0;
+
+    while let Some(scancode) = scancodes.next().await {
+        let dropped = dropped_scancodes();
+        if dropped != reported_drops {
+            println!("WARNING: scancode queue full; dropped {} bytes", dropped - reported_drops);
+            reported_drops = dropped;
+        }
+
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_scancode_queue_drops_when_full() {
+    let queue = ScancodeQueue::new();
+    for i in 0..SCANCODE_QUEUE_CAPACITY {
+        assert_eq!(queue.push(i as u8), Ok(()));
+    }
+    assert_eq!(queue.push(0xff), Err(0xff));
+    for i in 0..SCANCODE_QUEUE_CAPACITY {
+        assert_eq!(queue.pop(), Some(i as u8));
+    }
+    assert_eq!(queue.pop(), None);
+}
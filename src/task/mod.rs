@@ -0,0 +1,56 @@
+// Async/Await:
+// A `Future` represents a value that might not be available yet. Instead of blocking until the value is
+// ready, the executor calls `poll` on it and the future either returns `Poll::Ready(value)` or
+// `Poll::Pending`. When a future returns `Pending` it registers the `Waker` from the passed `Context`, and
+// whoever completes the work (e.g. an interrupt handler) calls `wake` on it so the executor knows it
+// should poll that future again. This is cooperative multitasking: every task runs until it returns
+// `Pending`, so all tasks can share a single call stack.
+
+// `async fn` is transformed by the compiler into a state machine that implements `Future`. The state
+// machine may contain references to its own fields, so it must not be moved in memory once it is
+// polled. That is why tasks are stored as `Pin<Box<dyn Future>>` on the heap.
+
+pub mod executor;
+pub mod keyboard;
+
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>, // Pinned so the future is never moved after first poll
+}
+
+impl Task {
+    /// Creates a new task from the given future.
+    ///
+    /// Tasks do not return anything, their only purpose is their side effects.
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    /// Polls the wrapped future with the given context.
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Returns a new unique id, ids are never reused.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        // Relaxed because we only need each id to be unique, no other memory is synchronized through it
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,456 @@
+// Test Runner:
+// The custom test framework collects every `#[test_case]` item and hands them to `test_runner` as
+// `&[&dyn Testable]`. Plain functions only have a name; `TestCase` constants carry attributes as well,
+// so a test can be ignored or be expected to panic:
+//
+//     #[test_case]
+//     const DIVIDES_BY_ZERO: TestCase = TestCase::new("divides_by_zero", divides_by_zero).expect_panic();
+//
+// Each test is timed with the PIT tick clock (so its resolution is one tick, 1 ms once `init` ran).
+// Tests can be selected by a name filter: a test runs only if its name contains the filter. The filter
+// comes from the fw_cfg file `opt/enigma/test-filter` (`-fw_cfg name=opt/enigma/test-filter,string=heap`
+// in the QEMU arguments) or from the `ENIGMA_TEST_FILTER` environment variable at build time. The
+// results are printed in the format chosen in `report`.
+
+// We can't unwind, so a panic never returns into the runner. Instead the panic handler records the result
+// of the running test and runs the remaining tests itself, on top of the abandoned stack of the panicked
+// one. Locks the panicked test held stay locked (except the VGA writer and the serial port, which the
+// runner needs itself), so a later test using the same lock hangs. Once every test ran, a summary is
+// printed and QEMU exits with `Success` or, if any test failed, with `TestsFailed`. A panic outside of a
+// test (e.g. during `init`) still exits with `Failed` right away. A test that doesn't finish within its
+// budget is stopped by the `watchdog`.
+
+use crate::{backtrace::Backtrace, exit_qemu, fw_cfg, hlt_loop, serial, serial_println};
+use crate::time::{Duration, Instant};
+use crate::{vga_buffer, QemuExitCode};
+use core::{
+    any,
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod report;
+pub mod watchdog;
+
+const FILTER_FILE: &str = "opt/enigma/test-filter";
+const FORMAT_FILE: &str = "opt/enigma/test-format";
+const MAX_FILTER_LEN: usize = 64;
+/// Names of failed tests listed in the summary, the count is always complete.
+const MAX_LISTED_FAILURES: usize = 16;
+
+/// Whether a test is expected to panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShouldPanic {
+    No,
+    Yes,
+    WithMessage(&'static str), // ---> The panic message must contain this text
+}
+
+/// Shared with the panic handler through a static, so it has to be `Sync`.
+pub trait Testable: Sync {
+    fn run(&self);
+
+    fn name(&self) -> &'static str;
+
+    /// Ignored tests are listed, but not run.
+    fn ignored(&self) -> bool {
+        false
+    }
+
+    fn should_panic(&self) -> ShouldPanic {
+        ShouldPanic::No
+    }
+
+    /// The watchdog stops the run if the test takes longer.
+    fn timeout(&self) -> Duration {
+        watchdog::DEFAULT_TIMEOUT
+    }
+}
+
+impl<T: Fn() + Sync> Testable for T {
+    fn run(&self) {
+        self();
+    }
+
+    fn name(&self) -> &'static str {
+        any::type_name::<T>() // Function name
+    }
+}
+
+/// A test function with attributes, built in a `const` item marked with `#[test_case]`.
+#[derive(Debug, Clone, Copy)]
+pub struct TestCase {
+    name: &'static str,
+    test: fn(),
+    ignored: bool,
+    should_panic: ShouldPanic,
+    timeout: Duration,
+}
+
+impl TestCase {
+    pub const fn new(name: &'static str, test: fn()) -> Self {
+        TestCase {
+            name,
+            test,
+            ignored: false,
+            should_panic: ShouldPanic::No,
+            timeout: watchdog::DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// The equivalent of `#[ignore]`.
+    pub const fn ignore(mut self) -> Self {
+        self.ignored = true;
+        self
+    }
+
+    /// The equivalent of `#[should_panic]`, the test fails if it returns.
+    pub const fn expect_panic(mut self) -> Self {
+        self.should_panic = ShouldPanic::Yes;
+        self
+    }
+
+    /// The equivalent of `#[should_panic(expected = "...")]`.
+    pub const fn expect_panic_with(mut self, message: &'static str) -> Self {
+        self.should_panic = ShouldPanic::WithMessage(message);
+        self
+    }
+
+    /// Replaces the `watchdog::DEFAULT_TIMEOUT` budget.
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Testable for TestCase {
+    fn run(&self) {
+        (self.test)();
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn ignored(&self) -> bool {
+        self.ignored
+    }
+
+    fn should_panic(&self) -> ShouldPanic {
+        self.should_panic
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+/// State of the test run, shared between the runner and the panic handler.
+struct Run {
+    tests: &'static [&'static dyn Testable],
+    next: usize,
+    current: Option<Current>,
+    started: Instant,
+    filter: [u8; MAX_FILTER_LEN],
+    filter_len: usize,
+    passed: usize,
+    failed: usize,
+    ignored: usize,
+    filtered_out: usize,
+    failures: [&'static str; MAX_LISTED_FAILURES],
+}
+
+impl Run {
+    fn matches_filter(&self, name: &str) -> bool {
+        matches_filter(&self.filter[..self.filter_len], name)
+    }
+
+    fn listed_failures(&self) -> &[&'static str] {
+        &self.failures[..self.failed.min(MAX_LISTED_FAILURES)]
+    }
+}
+
+/// The running test.
+#[derive(Clone, Copy)]
+struct Current {
+    test: &'static dyn Testable,
+    number: usize, // ---> Position in the report, counting the ignored tests
+    started: Instant,
+    interrupts_enabled: bool, // ---> Interrupt flag when the test started, restored after a panic
+}
+
+impl Current {
+    fn millis(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    // SAFETY: neither the runner nor the panic handler ever return, so `tests` outlives the run
+    let tests =
+        unsafe { core::mem::transmute::<&[&dyn Testable], &'static [&'static dyn Testable]>(tests) };
+    let mut filter = [0; MAX_FILTER_LEN];
+    let filter_len = fw_cfg::read_file(FILTER_FILE, &mut filter).unwrap_or_else(|| {
+        let build_time = option_env!("ENIGMA_TEST_FILTER").unwrap_or("").as_bytes();
+        let len = build_time.len().min(MAX_FILTER_LEN);
+        filter[..len].copy_from_slice(&build_time[..len]);
+        len
+    });
+
+    let mut format = [0; 8];
+    if let Some(len) = fw_cfg::read_file(FORMAT_FILE, &mut format) {
+        match report::Format::from_name(&format[..len]) {
+            Some(format) => report::set_format(format),
+            None => serial_println!("unknown test output format in {}", FORMAT_FILE),
+        }
+    }
+
+    let run = Run {
+        tests,
+        next: 0,
+        current: None,
+        started: Instant::now(),
+        filter,
+        filter_len,
+        passed: 0,
+        failed: 0,
+        ignored: 0,
+        filtered_out: 0,
+        failures: [""; MAX_LISTED_FAILURES],
+    };
+    let planned = tests.iter().filter(|test| run.matches_filter(test.name())).count();
+    let ignored = tests
+        .iter()
+        .filter(|test| run.matches_filter(test.name()) && test.ignored())
+        .count();
+    report::run_started(&run, planned, ignored);
+    *RUN.lock() = Some(run);
+    run_remaining();
+}
+
+/// Runs the tests after the current one, then prints the summary and exits QEMU.
+fn run_remaining() -> ! {
+    while let Some(test) = next_test() {
+        watchdog::arm(test.timeout());
+        test.run();
+        let current = take_current().expect("test runner state lost");
+        if test.should_panic() == ShouldPanic::No {
+            report::test_passed(current.number, test.name(), current.millis());
+            record(test, true);
+        } else {
+            let reason = "test did not panic as expected";
+            report::test_failed(current.number, test.name(), current.millis(), &reason);
+            record(test, false);
+        }
+    }
+    finish()
+}
+
+/// Skips the ignored and filtered out tests, reports the next test as started and marks it as running.
+fn next_test() -> Option<&'static dyn Testable> {
+    let mut run = RUN.lock();
+    let run = run.as_mut()?;
+    while let Some(&test) = run.tests.get(run.next) {
+        run.next += 1;
+        let number = run.passed + run.failed + run.ignored + 1;
+        if !run.matches_filter(test.name()) {
+            run.filtered_out += 1;
+        } else if test.ignored() {
+            report::test_ignored(number, test.name());
+            run.ignored += 1;
+        } else {
+            report::test_started(test.name());
+            run.current = Some(Current {
+                test,
+                number,
+                started: Instant::now(),
+                interrupts_enabled: interrupts::are_enabled(),
+            });
+            return Some(test);
+        }
+    }
+    None
+}
+
+/// Whether `name` contains `filter`, an empty filter matches every test.
+fn matches_filter(filter: &[u8], name: &str) -> bool {
+    filter.is_empty()
+        || name
+            .as_bytes()
+            .windows(filter.len())
+            .any(|window| window == filter)
+}
+
+fn take_current() -> Option<Current> {
+    watchdog::disarm();
+    RUN.try_lock()?.as_mut()?.current.take()
+}
+
+fn record(test: &'static dyn Testable, passed: bool) {
+    if let Some(run) = RUN.lock().as_mut() {
+        if passed {
+            run.passed += 1;
+        } else {
+            if run.failed < MAX_LISTED_FAILURES {
+                run.failures[run.failed] = test.name();
+            }
+            run.failed += 1;
+        }
+    }
+}
+
+/// Prints the summary and exits QEMU.
+fn finish() -> ! {
+    let run = RUN.lock().take().expect("test runner state lost");
+    report::run_finished(&run);
+    exit_qemu(if run.failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::TestsFailed
+    });
+    hlt_loop();
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    // the current test is taken out first, so a panic while reporting it ends up below
+    let Some(current) = take_current() else {
+        report::crashed(&format_args!("{}\n\n{}", info, Backtrace::here()));
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop();
+    };
+
+    // the frames holding these locks are never resumed
+    unsafe {
+        serial::SERIAL1.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+    let test = current.test;
+    let passed = match test.should_panic() {
+        ShouldPanic::No => false,
+        ShouldPanic::Yes => true,
+        ShouldPanic::WithMessage(expected) => {
+            let mut message = MessageBuffer::new();
+            let _ = write!(message, "{}", info);
+            matches_filter(expected.as_bytes(), message.as_str())
+        }
+    };
+    if passed {
+        report::test_passed(current.number, test.name(), current.millis());
+    } else if let ShouldPanic::WithMessage(expected) = test.should_panic() {
+        report::test_failed(
+            current.number,
+            test.name(),
+            current.millis(),
+            &format_args!("{}\n\nExpected a panic message containing `{}`", info, expected),
+        );
+    } else {
+        report::test_failed(
+            current.number,
+            test.name(),
+            current.millis(),
+            &format_args!("{}\n\n{}", info, Backtrace::here()),
+        );
+    }
+    record(test, passed);
+
+    // a test panicking with interrupts disabled would otherwise stop the tick clock for the rest, but
+    // tests that never enabled them (e.g. without an IDT) must not get them
+    if current.interrupts_enabled {
+        interrupts::enable();
+    } else {
+        interrupts::disable();
+    }
+    run_remaining();
+}
+
+/// Called by the watchdog from the timer interrupt, the test it interrupted at `rip` never resumes.
+fn timed_out(rip: u64, rbp: u64) -> ! {
+    // the test might be stuck on one of these
+    unsafe {
+        serial::SERIAL1.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+    match take_current() {
+        Some(current) => {
+            let test = current.test;
+            report::test_failed(
+                current.number,
+                test.name(),
+                current.millis(),
+                &format_args!(
+                    "test {} timed out after {} ms\nRIP {:#018x}\n{}",
+                    test.name(),
+                    test.timeout().as_millis(),
+                    rip,
+                    Backtrace { rip, rbp }
+                ),
+            );
+            record(test, false);
+        }
+        None => serial_println!("watchdog fired outside of a test at RIP {:#018x}", rip),
+    }
+    if let Some(run) = RUN.try_lock().and_then(|mut run| run.take()) {
+        report::run_finished(&run);
+    }
+    exit_qemu(QemuExitCode::TimedOut);
+    hlt_loop();
+}
+
+/// Collects a panic message without the heap, cut off after 256 bytes.
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl MessageBuffer {
+    fn new() -> Self {
+        MessageBuffer {
+            bytes: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // cutting off may split a character
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(message) => message,
+            Err(error) => core::str::from_utf8(&self.bytes[..error.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn filter_matches_substring() {
+    assert!(matches_filter(b"", "enigma::allocator::heap"));
+    assert!(matches_filter(b"alloc", "enigma::allocator::heap"));
+    assert!(!matches_filter(b"vga", "enigma::allocator::heap"));
+    assert!(!matches_filter(b"enigma::allocator::heap::grows", "enigma::allocator::heap"));
+}
+
+#[test_case]
+const IGNORED_IS_NOT_RUN: TestCase =
+    TestCase::new("enigma::testing::ignored_is_not_run", || panic!("ignored test ran")).ignore();
+
+#[test_case]
+const EXPECTED_PANIC: TestCase =
+    TestCase::new("enigma::testing::expected_panic", || panic!("expected by the runner"))
+        .expect_panic_with("expected by the runner");
+
+#[test_case]
+fn runs_after_expected_panic() {
+    // the run goes on after `EXPECTED_PANIC`
+    assert_eq!(1 + 1, 2);
+}
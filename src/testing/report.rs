@@ -0,0 +1,313 @@
+// Test Output Formats:
+// The runner reports over serial in one of three formats. `Text` is meant for humans. `Tap` is TAP
+// version 14: one `ok`/`not ok` line per test with the duration and the panic message in a YAML block.
+// `Junit` is JUnit XML with one `<testcase>` per test. With the machine-readable formats the QEMU serial
+// log can be handed to a test dashboard directly.
+
+// The format is chosen with the `test-tap` or `test-junit` feature, or at boot with the fw_cfg file
+// `opt/enigma/test-format` (`tap`, `junit` or `text`), which wins over the feature. Tests that print to
+// serial themselves end up between the lines of the report; TAP consumers skip such lines, but a `<` or
+// `&` in them breaks the XML.
+
+use super::Run;
+use crate::{serial_print, serial_println};
+use core::{
+    fmt::{self, Display, Write},
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Format {
+    Text,
+    Tap,
+    Junit,
+}
+
+impl Format {
+    /// The format selected by cargo feature.
+    pub const fn built_in() -> Self {
+        if cfg!(feature = "test-junit") {
+            Format::Junit
+        } else if cfg!(feature = "test-tap") {
+            Format::Tap
+        } else {
+            Format::Text
+        }
+    }
+
+    /// Parses the value of the boot parameter, surrounding whitespace is ignored.
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        match name.trim_ascii() {
+            b"text" => Some(Format::Text),
+            b"tap" => Some(Format::Tap),
+            b"junit" => Some(Format::Junit),
+            _ => None,
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(Format::built_in() as u8);
+
+pub(super) fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => Format::Tap,
+        2 => Format::Junit,
+        _ => Format::Text,
+    }
+}
+
+/// `planned` is the number of tests that pass the filter, including the ignored ones.
+pub(super) fn run_started(run: &Run, planned: usize, ignored: usize) {
+    let filter = core::str::from_utf8(&run.filter[..run.filter_len]).unwrap_or("?");
+    match format() {
+        Format::Text => {
+            serial_println!("Running {} tests", run.tests.len());
+            if !filter.is_empty() {
+                serial_println!("filter: {}", filter);
+            }
+        }
+        Format::Tap => {
+            serial_println!("TAP version 14");
+            serial_println!("1..{}", planned);
+            if !filter.is_empty() {
+                serial_println!("# filter: {}", filter);
+            }
+        }
+        Format::Junit => {
+            serial_println!(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+            // the failures are only known at the end, readers count the <failure> elements
+            serial_println!(
+                r#"<testsuite name="{}" tests="{}" skipped="{}">"#,
+                Xml(suite_name(run)),
+                planned,
+                ignored
+            );
+        }
+    }
+}
+
+/// Called right before the test runs.
+pub(super) fn test_started(name: &str) {
+    if format() == Format::Text {
+        serial_print!("{}...\t", name);
+    }
+}
+
+/// `number` counts every reported test, starting at 1.
+pub(super) fn test_ignored(number: usize, name: &str) {
+    match format() {
+        Format::Text => serial_println!("{}...\t[ignored]", name),
+        Format::Tap => serial_println!("ok {} - {} # SKIP ignored", number, name),
+        Format::Junit => {
+            let (class, name) = split_name(name);
+            serial_println!(
+                r#"  <testcase classname="{}" name="{}" time="0"><skipped/></testcase>"#,
+                Xml(class),
+                Xml(name)
+            );
+        }
+    }
+}
+
+pub(super) fn test_passed(number: usize, name: &str, millis: u64) {
+    match format() {
+        Format::Text => serial_println!("[ok] ({} ms)", millis),
+        Format::Tap => {
+            serial_println!("ok {} - {}", number, name);
+            serial_println!("  ---\n  duration_ms: {}\n  ...", millis);
+        }
+        Format::Junit => {
+            let (class, name) = split_name(name);
+            serial_println!(
+                r#"  <testcase classname="{}" name="{}" time="{}"/>"#,
+                Xml(class),
+                Xml(name),
+                Seconds(millis)
+            );
+        }
+    }
+}
+
+pub(super) fn test_failed(number: usize, name: &str, millis: u64, reason: &dyn Display) {
+    match format() {
+        Format::Text => {
+            serial_println!("[failed] ({} ms)\n", millis);
+            serial_println!("Error: {}\n", reason);
+        }
+        Format::Tap => {
+            serial_println!("not ok {} - {}", number, name);
+            serial_println!("  ---\n  duration_ms: {}", millis);
+            serial_println!("  message: |\n    {}\n  ...", Prefixed("    ", reason));
+        }
+        Format::Junit => {
+            let (class, name) = split_name(name);
+            serial_println!(
+                r#"  <testcase classname="{}" name="{}" time="{}">"#,
+                Xml(class),
+                Xml(name),
+                Seconds(millis)
+            );
+            serial_println!(r#"    <failure message="{}"/>"#, Xml(reason));
+            serial_println!("  </testcase>");
+        }
+    }
+}
+
+pub(super) fn run_finished(run: &Run) {
+    let result = if run.failed == 0 { "ok" } else { "FAILED" };
+    let millis = run.started.elapsed().as_millis();
+    match format() {
+        Format::Text => {
+            serial_println!();
+            if run.failed > 0 {
+                serial_println!("failures:");
+                for name in run.listed_failures() {
+                    serial_println!("    {}", name);
+                }
+                if run.failed > run.listed_failures().len() {
+                    serial_println!("    and {} more", run.failed - run.listed_failures().len());
+                }
+                serial_println!();
+            }
+            serial_println!(
+                "test result: {}. {} passed; {} failed; {} ignored; {} filtered out; finished in {} ms",
+                result,
+                run.passed,
+                run.failed,
+                run.ignored,
+                run.filtered_out,
+                millis
+            );
+        }
+        Format::Tap => serial_println!(
+            "# test result: {}. {} passed; {} failed; {} ignored; {} filtered out; finished in {} ms",
+            result,
+            run.passed,
+            run.failed,
+            run.ignored,
+            run.filtered_out,
+            millis
+        ),
+        Format::Junit => serial_println!("</testsuite>"),
+    }
+}
+
+/// A panic outside of a test, the run ends right after.
+pub(super) fn crashed(info: &dyn Display) {
+    match format() {
+        Format::Text => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: {}\n", info);
+        }
+        Format::Tap => {
+            serial_println!("Bail out! panic outside of a test");
+            serial_println!("# {}", Prefixed("# ", info));
+        }
+        Format::Junit => {
+            serial_println!(r#"  <testcase classname="" name="(outside of a test)">"#);
+            serial_println!(r#"    <error message="{}"/>"#, Xml(info));
+            serial_println!("  </testcase>");
+            serial_println!("</testsuite>");
+        }
+    }
+}
+
+/// The crate part of the first test name, e.g. `heap_allocation` for an integration test.
+fn suite_name(run: &Run) -> &'static str {
+    run.tests
+        .first()
+        .and_then(|test| test.name().split("::").next())
+        .unwrap_or("enigma")
+}
+
+/// Splits `heap_allocation::simple_allocation` into class `heap_allocation` and name `simple_allocation`.
+fn split_name(name: &str) -> (&str, &str) {
+    name.rsplit_once("::").unwrap_or(("", name))
+}
+
+/// Milliseconds as decimal seconds.
+struct Seconds(u64);
+
+impl Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// Escapes the markup characters, and line breaks so the text can be used in an attribute.
+struct Xml<T>(T);
+
+impl<T: Display> Display for Xml<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(XmlEscaper(f), "{}", self.0)
+    }
+}
+
+struct XmlEscaper<'a, 'b>(&'a mut fmt::Formatter<'b>);
+
+impl Write for XmlEscaper<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '&' => self.0.write_str("&amp;")?,
+                '<' => self.0.write_str("&lt;")?,
+                '>' => self.0.write_str("&gt;")?,
+                '"' => self.0.write_str("&quot;")?,
+                '\n' => self.0.write_str("&#10;")?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts every line after the first with a prefix, e.g. the indentation of a YAML block scalar in TAP.
+struct Prefixed<T>(&'static str, T);
+
+impl<T: Display> Display for Prefixed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(Prefixer(f, self.0), "{}", self.1)
+    }
+}
+
+struct Prefixer<'a, 'b>(&'a mut fmt::Formatter<'b>, &'static str);
+
+impl Write for Prefixer<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_char('\n')?;
+                self.0.write_str(self.1)?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn escapes_xml() {
+    let mut out = super::MessageBuffer::new();
+    write!(out, "{}", Xml("a < b && \"c\"\nd")).unwrap();
+    assert_eq!(out.as_str(), "a &lt; b &amp;&amp; &quot;c&quot;&#10;d");
+}
+
+#[test_case]
+fn indents_yaml_block() {
+    let mut out = super::MessageBuffer::new();
+    write!(out, "{}", Prefixed("    ", "panicked at src/lib.rs:1:1:\nmessage")).unwrap();
+    assert_eq!(out.as_str(), "panicked at src/lib.rs:1:1:\n    message");
+}
+
+#[test_case]
+fn parses_format_names() {
+    assert_eq!(Format::from_name(b"tap\n"), Some(Format::Tap));
+    assert_eq!(Format::from_name(b"junit"), Some(Format::Junit));
+    assert_eq!(Format::from_name(b"xml"), None);
+    assert_eq!(split_name("heap::simple_allocation"), ("heap", "simple_allocation"));
+}
@@ -0,0 +1,38 @@
+// Test Watchdog:
+// A test that deadlocks (e.g. on a lock that a previous test left locked) would otherwise hang until the
+// host kills QEMU, and everything we could have learned about it is lost. So the runner arms the watchdog
+// with the budget of each test before running it. The timer interrupt checks the deadline on every tick;
+// once it passed, the runner reports the test as timed out together with the interrupted RIP and a stack
+// backtrace, prints the summary and exits QEMU with `QemuExitCode::TimedOut`. Without timer interrupts
+// (tests that don't call `enigma::init`) the watchdog never fires.
+
+use crate::{thread::switch::SavedContext, time};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Budget of a test that doesn't set its own with `TestCase::with_timeout`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tick at which the running test times out, 0 while disarmed.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn arm(timeout: Duration) {
+    let deadline = time::ticks() + time::duration_to_ticks(timeout).max(1);
+    DEADLINE.store(deadline, Ordering::SeqCst);
+}
+
+pub(super) fn disarm() {
+    DEADLINE.store(0, Ordering::SeqCst);
+}
+
+/// Called by the timer interrupt handler on every tick with the registers of the interrupted code.
+pub(crate) fn check(context: &SavedContext) {
+    let deadline = DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || time::ticks() < deadline {
+        return;
+    }
+    disarm();
+    super::timed_out(context.rip, context.rbp);
+}
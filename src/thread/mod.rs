@@ -0,0 +1,173 @@
+// Preemptive Multitasking:
+// With async/await every task has to give up the CPU by itself, a task that computes for a long time
+// blocks all others. Kernel threads instead are interrupted by the timer: each thread has its own stack,
+// and on a timer interrupt the scheduler saves the registers of the running thread on its stack and
+// resumes another one. No thread needs to cooperate to let the others run.
+
+pub mod scheduler;
+pub mod switch;
+
+use crate::memory::stack::{self, Stack};
+use crate::time;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use scheduler::SCHEDULER;
+use switch::SavedContext;
+use x86_64::instructions::interrupts;
+
+/// Size of the stack of every spawned thread, in 4 KiB pages.
+pub const STACK_PAGES: u64 = 4; // 16 KiB
+
+/// Number of timer ticks a thread may run before it is preempted.
+pub const TIME_SLICE_TICKS: u64 = time::TIMER_FREQUENCY_HZ as u64 / 100; // 10 ms
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// Returns a new unique id, ids are never reused.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ThreadState {
+    Running,  // ---> Currently executing on the CPU
+    Ready,    // ---> Waiting in the ready queue
+    Finished, // ---> Called `exit`, waits to be joined
+}
+
+pub(crate) struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    context: *mut SavedContext, // ---> Saved stack pointer, only valid while the thread is not running
+    _stack: Option<Stack>,      // ---> None for the boot thread, which runs on the bootloader's stack
+}
+
+// The context pointer points into the thread's own stack, which is only accessed by the scheduler
+unsafe impl Send for Thread {}
+
+/// Turns the currently running code into the boot thread and enables preemption.
+///
+/// Must be called once after the heap is initialized and the kernel memory is installed.
+pub fn init() {
+    let boot_thread = Thread {
+        id: ThreadId::new(),
+        state: ThreadState::Running,
+        context: core::ptr::null_mut(), // saved on the first switch away from it
+        _stack: None,
+    };
+    let mut idle_thread = Thread::new(Box::new(|| crate::hlt_loop()));
+    idle_thread.state = ThreadState::Ready;
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        assert!(scheduler.is_none(), "thread::init called twice");
+        *scheduler = Some(scheduler::Scheduler::new(boot_thread, idle_thread));
+    });
+}
+
+impl Thread {
+    /// Creates a thread with its own stack that starts by calling `f`.
+    fn new(f: Box<dyn FnOnce() + Send>) -> Thread {
+        // Guarded stack, an overflow page faults instead of corrupting memory
+        let stack = stack::alloc_stack(STACK_PAGES).expect("failed to allocate thread stack");
+        let stack_top = stack.top();
+        // Double box because `dyn FnOnce` is a fat pointer which does not fit in a register
+        let arg = Box::into_raw(Box::new(f)) as usize;
+        Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Ready,
+            context: switch::init_context(stack_top, thread_entry, arg),
+            _stack: Some(stack),
+        }
+    }
+}
+
+/// First function executed by every spawned thread.
+extern "C" fn thread_entry(arg: usize) -> ! {
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send>) };
+    f();
+    exit();
+}
+
+/// Spawns a new thread that runs `f` and is scheduled round-robin with all other threads.
+///
+/// The stack of the thread is released for reuse when it is joined.
+pub fn spawn<F>(f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new(Box::new(f));
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("threading not initialized")
+            .add(thread);
+    });
+    JoinHandle { id }
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    switch::yield_interrupt();
+}
+
+/// Returns the id of the running thread, `None` before `init` was called.
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_ref().map(|s| s.current))
+}
+
+/// Terminates the current thread.
+pub fn exit() -> ! {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("threading not initialized");
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().state = ThreadState::Finished;
+    });
+    yield_now();
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Handle to wait for a spawned thread to finish.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Yields until the thread has finished, then releases its stack.
+    pub fn join(self) {
+        assert_ne!(Some(self.id), current_id(), "thread tried to join itself");
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                let mut scheduler = SCHEDULER.lock();
+                let scheduler = scheduler.as_mut().expect("threading not initialized");
+                match scheduler.threads.get(&self.id) {
+                    Some(thread) if thread.state == ThreadState::Finished => {
+                        scheduler.threads.remove(&self.id)
+                    }
+                    _ => None,
+                }
+            });
+            if let Some(thread) = finished {
+                drop(thread); // release the stack outside of the scheduler lock
+                return;
+            }
+            yield_now();
+        }
+    }
+}
@@ -0,0 +1,121 @@
+// Round-robin scheduler:
+// Every thread that can run waits in the `ready` queue. On each timer tick the running thread uses up
+// part of its time slice; once it is used up (or the thread yields) its context is saved and the thread
+// at the front of the queue is resumed, while the old thread goes to the back. The idle thread is not
+// part of the queue, it only runs when nothing else can.
+
+// `schedule` runs inside interrupt handlers, so it must never allocate or block: it only uses
+// `try_lock` and keeps the length of the `ready` queue unchanged by popping before pushing.
+
+use super::{switch::SavedContext, Thread, ThreadId, ThreadState, TIME_SLICE_TICKS};
+use alloc::collections::{BTreeMap, VecDeque};
+use spin::Mutex;
+
+pub(super) static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+pub(super) struct Scheduler {
+    pub(super) threads: BTreeMap<ThreadId, Thread>,
+    pub(super) ready: VecDeque<ThreadId>,
+    pub(super) current: ThreadId,
+    idle: ThreadId,
+    slice_left: u64, // ---> Timer ticks left before the current thread is preempted
+}
+
+impl Scheduler {
+    /// Creates a scheduler whose current thread is `boot_thread`.
+    pub(super) fn new(boot_thread: Thread, idle_thread: Thread) -> Self {
+        let current = boot_thread.id;
+        let idle = idle_thread.id;
+        let mut threads = BTreeMap::new();
+        threads.insert(boot_thread.id, boot_thread);
+        threads.insert(idle_thread.id, idle_thread);
+        Scheduler {
+            threads,
+            ready: VecDeque::new(),
+            current,
+            idle,
+            slice_left: TIME_SLICE_TICKS,
+        }
+    }
+
+    /// Adds a new thread and queues it to run.
+    ///
+    /// Might allocate, so it must not be called from an interrupt handler.
+    pub(super) fn add(&mut self, thread: Thread) {
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        self.ready.push_back(id);
+    }
+
+    /// Saves `context` as the context of the current thread and returns the context of the thread that
+    /// should run next.
+    fn switch(&mut self, context: *mut SavedContext) -> *mut SavedContext {
+        let current = self.current;
+        let current_runnable = match self.threads.get_mut(&current) {
+            Some(thread) => {
+                thread.context = context;
+                thread.state == ThreadState::Running
+            }
+            None => false,
+        };
+
+        let next = match self.next_ready() {
+            Some(next) => next,
+            None if current_runnable => {
+                // nobody else wants to run, continue with a fresh time slice
+                self.slice_left = TIME_SLICE_TICKS;
+                return context;
+            }
+            None => self.idle,
+        };
+
+        if current_runnable {
+            self.threads.get_mut(&current).unwrap().state = ThreadState::Ready;
+            if current != self.idle {
+                // one id was just popped by `next_ready`, so this does not grow the queue
+                self.ready.push_back(current);
+            }
+        }
+
+        let thread = self.threads.get_mut(&next).unwrap();
+        thread.state = ThreadState::Running;
+        self.current = next;
+        self.slice_left = TIME_SLICE_TICKS;
+        thread.context
+    }
+
+    /// Pops the next thread that is ready to run from the queue.
+    fn next_ready(&mut self) -> Option<ThreadId> {
+        while let Some(id) = self.ready.pop_front() {
+            match self.threads.get(&id) {
+                Some(thread) if thread.state == ThreadState::Ready => return Some(id),
+                _ => continue, // thread finished while it was queued
+            }
+        }
+        None
+    }
+}
+
+/// Entered from the timer (`preempt == true`) and yield interrupt entry stubs.
+///
+/// Returns the context to resume, which is `context` itself unless another thread should run.
+pub(crate) fn schedule(context: *mut SavedContext, preempt: bool) -> *mut SavedContext {
+    // If the scheduler is locked the interrupted code is in the middle of changing it, keep running
+    // it and try again on the next tick
+    let mut guard = match SCHEDULER.try_lock() {
+        Some(guard) => guard,
+        None => return context,
+    };
+    let scheduler = match guard.as_mut() {
+        Some(scheduler) => scheduler,
+        None => return context, // threading not initialized yet
+    };
+
+    if preempt {
+        scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+        if scheduler.slice_left > 0 {
+            return context;
+        }
+    }
+    scheduler.switch(context)
+}
@@ -0,0 +1,150 @@
+// Context Switching:
+// To switch threads we need the complete register state of the interrupted thread, which the
+// `x86-interrupt` calling convention does not give us. So the timer and yield interrupts enter through
+// the small assembly stubs below. They push all general purpose registers on top of the interrupt stack
+// frame the CPU already pushed, which leaves a complete `SavedContext` on the stack of the interrupted
+// thread. The stub passes its address to a Rust function that returns the address of the context to
+// resume, loads it into `rsp`, pops the registers and returns with `iretq`. Switching threads is thus
+// just returning a different stack pointer.
+
+use core::arch::global_asm;
+use core::mem;
+use x86_64::VirtAddr;
+
+/// Interrupt vector used by `yield_now` to enter the scheduler voluntarily.
+///
+/// Must match the `int` instruction in `yield_interrupt`.
+pub const YIELD_VECTOR: usize = 0x81;
+
+/// Register state of a thread that is not running, stored at the top of its stack.
+///
+/// The field order must match the push order in the entry stubs below.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SavedContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by the CPU on interrupt entry
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+macro_rules! push_registers {
+    () => {
+        "push rax\npush rbx\npush rcx\npush rdx\npush rsi\npush rdi\npush rbp\npush r8\npush r9\n\
+         push r10\npush r11\npush r12\npush r13\npush r14\npush r15\n"
+    };
+}
+
+macro_rules! pop_registers {
+    () => {
+        "pop r15\npop r14\npop r13\npop r12\npop r11\npop r10\npop r9\npop r8\npop rbp\npop rdi\n\
+         pop rsi\npop rdx\npop rcx\npop rbx\npop rax\n"
+    };
+}
+
+// Also used by the exception entry stubs
+pub(crate) use {pop_registers, push_registers};
+
+// The CPU aligns `rsp` to 16 bytes before pushing the 5 qword interrupt frame, after our 15 pushes it
+// is 16 byte aligned again, as the System V ABI requires at a `call`.
+global_asm!(
+    ".global timer_interrupt_entry",
+    "timer_interrupt_entry:",
+    push_registers!(),
+    "cld", // ABI requires direction flag to be clear on function entry
+    "mov rdi, rsp",
+    "call {timer}",
+    "mov rsp, rax",
+    pop_registers!(),
+    "iretq",
+    "",
+    ".global yield_interrupt_entry",
+    "yield_interrupt_entry:",
+    push_registers!(),
+    "cld",
+    "mov rdi, rsp",
+    "call {yield}",
+    "mov rsp, rax",
+    pop_registers!(),
+    "iretq",
+    timer = sym crate::interrupts::timer_interrupt_handler,
+    yield = sym yield_interrupt_handler,
+);
+
+extern "C" {
+    fn timer_interrupt_entry();
+    fn yield_interrupt_entry();
+}
+
+/// Address of the timer interrupt entry stub, to be installed in the IDT.
+pub fn timer_entry_addr() -> VirtAddr {
+    VirtAddr::new(timer_interrupt_entry as usize as u64)
+}
+
+/// Address of the yield interrupt entry stub, to be installed in the IDT.
+pub fn yield_entry_addr() -> VirtAddr {
+    VirtAddr::new(yield_interrupt_entry as usize as u64)
+}
+
+extern "C" fn yield_interrupt_handler(context: *mut SavedContext) -> *mut SavedContext {
+    super::scheduler::schedule(context, false)
+}
+
+/// Enters the scheduler through the yield interrupt.
+pub(super) fn yield_interrupt() {
+    // Works with interrupts disabled too, `int` is not maskable and `iretq` restores the flags
+    unsafe { core::arch::asm!("int 0x81") };
+}
+
+/// Builds the context of a thread that has never run on the given stack.
+///
+/// When the context is resumed the thread starts executing `entry` with `arg` as its first argument,
+/// with interrupts enabled. Returns the address of the context, which is the thread's saved stack
+/// pointer.
+pub(super) fn init_context(
+    stack_top: VirtAddr,
+    entry: extern "C" fn(usize) -> !,
+    arg: usize,
+) -> *mut SavedContext {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+    use x86_64::registers::rflags::RFlags;
+
+    // `entry` expects to be called, which pushes a return address on a 16 byte aligned stack
+    let entry_rsp = stack_top.align_down(16u64) - 8u64;
+    let context_addr = (entry_rsp - mem::size_of::<SavedContext>() as u64).align_down(16u64);
+
+    let context = SavedContext {
+        rdi: arg as u64, // first argument in the System V ABI
+        rip: entry as usize as u64,
+        cs: u64::from(CS::get_reg().0),
+        // bit 1 is reserved and always set
+        rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+        rsp: entry_rsp.as_u64(),
+        ss: u64::from(SS::get_reg().0),
+        ..SavedContext::default()
+    };
+
+    unsafe {
+        entry_rsp.as_mut_ptr::<u64>().write(0); // fake return address, `entry` never returns
+        let context_ptr = context_addr.as_mut_ptr::<SavedContext>();
+        context_ptr.write(context);
+        context_ptr
+    }
+}
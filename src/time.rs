@@ -0,0 +1,242 @@
+// Timekeeping:
+// The 8254 PIT (Programmable Interval Timer) has an oscillator running at ~1.193182 MHz. Channel 0 divides
+// it by a programmable 16 bit divisor and raises IRQ 0 every time the counter reaches zero, so by choosing
+// the divisor we choose the frequency of the timer interrupt. Counting those interrupts gives us a
+// monotonic clock with a resolution of one tick.
+
+// Sleeping tasks register their waker in `TIMER_QUEUE`, sorted by deadline. The timer interrupt wakes the
+// ones whose deadline passed. It must not allocate or block, so it only uses `try_lock` and
+// `wake_by_ref`; entries are inserted and removed by the sleeping tasks themselves.
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    ops::{Add, Sub},
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+pub use core::time::Duration;
+
+/// Frequency of the oscillator driving the PIT.
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+/// Frequency of the timer interrupt used by the kernel.
+pub const TIMER_FREQUENCY_HZ: u32 = 1000; // 1 ms per tick
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Power-on default of the PIT (divisor 65536) until `init_pit` is called
+static FREQUENCY_HZ: AtomicU32 = AtomicU32::new(PIT_BASE_FREQUENCY / 65536);
+
+static TIMER_QUEUE: Mutex<BTreeMap<(u64, u64), TimerEntry>> = Mutex::new(BTreeMap::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+struct TimerEntry {
+    waker: Waker,
+    fired: bool, // ---> Already woken, avoids waking the task again on every tick until it is polled
+}
+
+/// Programs channel 0 of the PIT to fire the timer interrupt `frequency_hz` times per second.
+pub fn init_pit(frequency_hz: u32) {
+    assert!(frequency_hz != 0, "the timer frequency must not be zero");
+    let divisor = (PIT_BASE_FREQUENCY / frequency_hz).clamp(1, 65535);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+
+    interrupts::without_interrupts(|| unsafe {
+        // channel 0, access mode lobyte/hibyte, mode 3 (square wave generator), binary counting
+        command.write(0b0011_0110);
+        channel0.write((divisor & 0xff) as u8);
+        channel0.write((divisor >> 8) as u8);
+    });
+    FREQUENCY_HZ.store(PIT_BASE_FREQUENCY / divisor, Ordering::Relaxed);
+}
+
+/// Returns the frequency the timer interrupt currently runs at.
+pub fn frequency_hz() -> u32 {
+    FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Called by the timer interrupt handler on every tick.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // If a task is modifying the queue right now, its entries are woken on the next tick
+    if let Some(mut queue) = TIMER_QUEUE.try_lock() {
+        for (_, entry) in queue.range_mut(..=(now, u64::MAX)) {
+            if !entry.fired {
+                entry.fired = true;
+                entry.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// Number of timer interrupts since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the timer interrupt was enabled.
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(0))
+}
+
+/// Converts a duration into timer ticks, rounding up. Saturates at `u64::MAX` for huge durations.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = u128::from(frequency_hz());
+    let ticks = (duration.as_nanos() * frequency + 999_999_999) / 1_000_000_000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = u128::from(ticks) * 1_000_000_000 / u128::from(frequency_hz());
+    Duration::from_nanos(nanos as u64)
+}
+
+/// A point in time of the monotonic tick clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    /// Time passed from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(duration)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Returns a future that completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    // the current tick is already partly over, one more tick makes sure sleeps never end early
+    sleep_until(Instant(ticks().saturating_add(duration_to_ticks(duration)).saturating_add(1)))
+}
+
+/// Returns a future that completes at `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+/// Future returned by `sleep`.
+pub struct Sleep {
+    deadline: Instant,
+    key: Option<(u64, u64)>, // ---> Key of our entry in `TIMER_QUEUE` once registered
+}
+
+impl Sleep {
+    fn unregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            interrupts::without_interrupts(|| TIMER_QUEUE.lock().remove(&key));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let deadline = self.deadline.0;
+        let key = *self
+            .key
+            .get_or_insert_with(|| (deadline, NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed)));
+        let entry = TimerEntry {
+            waker: cx.waker().clone(),
+            fired: false,
+        };
+        interrupts::without_interrupts(|| TIMER_QUEUE.lock().insert(key, entry));
+
+        // The deadline might have passed before our entry was in the queue
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+#[test_case]
+fn test_ticks_advance() {
+    let start = Instant::now();
+    while Instant::now() == start {
+        core::hint::spin_loop();
+    }
+    assert!(Instant::now() > start);
+}
+
+#[test_case]
+fn test_duration_rounds_up_to_ticks() {
+    assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    let one_second = duration_to_ticks(Duration::from_secs(1));
+    assert_eq!(u64::from(frequency_hz()), one_second);
+}
+
+#[test_case]
+fn test_huge_durations_saturate() {
+    assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+    assert_eq!((Instant::now() + Duration::MAX).ticks(), u64::MAX);
+}
+
+#[test_case]
+fn test_sleep_waits_for_deadline() {
+    use alloc::{sync::Arc, task::Wake};
+
+    struct NoopWaker;
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut context = Context::from_waker(&waker);
+    let duration = Duration::from_millis(20);
+    let start = Instant::now();
+    let mut timer = sleep(duration);
+    while Pin::new(&mut timer).poll(&mut context).is_pending() {
+        core::hint::spin_loop();
+    }
+    // the partial tick `start` fell into is not counted
+    assert!(start.elapsed() > duration);
+}
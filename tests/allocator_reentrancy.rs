@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    panic::PanicInfo,
+};
+use enigma::{
+    allocator::{linked_list::LinkedListAllocator, Locked},
+    testing::TestCase,
+};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+static HEAP: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    enigma::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+#[test_case]
+fn lock_disables_interrupts() {
+    assert!(interrupts::are_enabled());
+    {
+        let _heap = HEAP.lock();
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+const REENTRY_PANICS: TestCase =
+    TestCase::new("allocator_reentrancy::reentry_panics", reentry_panics)
+        .expect_panic_with("allocator re-entered");
+
+fn reentry_panics() {
+    let _heap = HEAP.lock();
+    // Allocating while the lock is held is what an allocation from inside the allocator would do
+    unsafe { HEAP.alloc(Layout::new::<u64>()) };
+}
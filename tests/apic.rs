@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use enigma::interrupts::apic::{self, ApicError, Mode};
+use enigma::time::Instant;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+// QEMU emulates an APIC for both `-machine pc` and `q35`
+#[test_case]
+fn switch_to_apic() {
+    let mode = apic::init().expect("APIC initialization failed");
+    assert_ne!(mode, Mode::Pic);
+    assert_eq!(apic::mode(), mode);
+    assert_eq!(apic::init(), Err(ApicError::AlreadyInitialized));
+}
+
+// The PIT is now routed through the I/O APIC, and its interrupts must still arrive
+#[test_case]
+fn timer_ticks_through_io_apic() {
+    let start = Instant::now();
+    while Instant::now().ticks() < start.ticks() + 10 {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use enigma::allocator::bump::Arena;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+#[test_case]
+fn vec_and_box_in_arena() {
+    let arena = Arena::new(4096).unwrap();
+    let mut vec = Vec::new_in(&arena);
+    for i in 0..100u64 {
+        vec.push(i);
+    }
+    let value = Box::new_in(41u64, &arena);
+    assert_eq!(vec.iter().sum::<u64>(), 99 * 100 / 2);
+    assert_eq!(*value + 1, 42);
+    assert!(arena.used() >= 100 * 8 + 8);
+}
+
+#[test_case]
+fn growing_last_allocation_stays_in_place() {
+    let arena = Arena::new(4096).unwrap();
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(16, &arena);
+    let start = vec.as_ptr();
+    vec.extend(core::iter::repeat(1).take(1000));
+    assert_eq!(vec.as_ptr(), start);
+    assert_eq!(arena.used(), vec.capacity());
+}
+
+#[test_case]
+fn full_arena_fails() {
+    let arena = Arena::new(4096).unwrap();
+    let mut vec: Vec<u8, _> = Vec::new_in(&arena);
+    assert!(vec.try_reserve(2 * arena.capacity()).is_err());
+    assert!(vec.try_reserve(arena.capacity()).is_ok());
+}
+
+#[test_case]
+fn reset_frees_everything() {
+    let mut arena = Arena::new(4096).unwrap();
+    {
+        let mut vec = Vec::new_in(&arena);
+        vec.extend(0..256u64);
+        // the Box is not the last allocation anymore, so freeing it gives nothing back
+        let value = Box::new_in(1u8, &arena);
+        drop(vec);
+        assert_eq!(*value, 1);
+    }
+    assert!(arena.used() >= 256 * 8);
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+    assert!(arena.high_water_mark() >= 256 * 8);
+}
+
+#[test_case]
+fn scopes_free_only_their_allocations() {
+    let arena = Arena::new(4096).unwrap();
+    let outer = Box::new_in(1u64, &arena);
+    let used_before = arena.used();
+    {
+        let scope = arena.scope();
+        let inner = Box::new_in([2u64; 16], &scope);
+        {
+            let nested = scope.scope();
+            let innermost = Box::new_in([3u64; 32], &nested);
+            assert_eq!(innermost[31], 3);
+            assert!(nested.used() >= 32 * 8);
+        }
+        assert_eq!(inner[15], 2);
+        assert!(scope.used() >= 16 * 8);
+    }
+    assert_eq!(arena.used(), used_before);
+    assert!(arena.high_water_mark() >= used_before + 48 * 8);
+    assert_eq!(*outer, 1);
+}
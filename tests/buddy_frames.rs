@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use enigma::memory::{
+    self,
+    buddy::{BuddyFrameAllocator, Zone, HUGE_PAGE_ORDER},
+};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+fn with_frames<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    memory::with_kernel_memory(|memory| f(&mut memory.frame_allocator))
+        .expect("memory not installed")
+}
+
+// Allocating and freeing a frame has to be reflected in the counters
+#[test_case]
+fn single_frames() {
+    with_frames(|frames| {
+        let free = frames.free_frames();
+        let used = frames.used_frames();
+        let frame: PhysFrame<Size4KiB> = frames.allocate_frame().expect("out of frames");
+        assert_eq!(frames.free_frames(), free - 1);
+        assert_eq!(frames.used_frames(), used + 1);
+        unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frames, frame) };
+        assert_eq!(frames.free_frames(), free);
+        assert_eq!(frames.used_frames(), used);
+    });
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    with_frames(|frames| {
+        let free = frames.free_frames();
+        let frame: PhysFrame<Size2MiB> = frames.allocate_frame().expect("no free 2 MiB block");
+        assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+        assert_eq!(frames.free_frames(), free - 512);
+        unsafe { FrameDeallocator::<Size2MiB>::deallocate_frame(frames, frame) };
+        assert_eq!(frames.free_frames(), free);
+    });
+}
+
+/// Allocates as many 2 MiB blocks as possible, frees them again and returns how many there were.
+fn count_huge_blocks(frames: &mut BuddyFrameAllocator) -> usize {
+    let mut blocks = [None; 1024];
+    for slot in blocks.iter_mut() {
+        *slot = frames.allocate_block(HUGE_PAGE_ORDER);
+    }
+    let count = blocks.iter().flatten().count();
+    for &block in blocks.iter().flatten() {
+        unsafe { frames.deallocate_block(block, HUGE_PAGE_ORDER) };
+    }
+    count
+}
+
+// Freeing a huge block as 512 single frames must merge them back, otherwise one 2 MiB block is lost
+#[test_case]
+fn freed_frames_are_merged() {
+    with_frames(|frames| {
+        let before = count_huge_blocks(frames);
+        let block = frames.allocate_block(HUGE_PAGE_ORDER).unwrap();
+        for i in 0..512 {
+            let frame = block + i;
+            unsafe { frames.deallocate_block(frame, 0) };
+        }
+        assert_eq!(count_huge_blocks(frames), before);
+    });
+}
+
+#[test_case]
+fn dma_zone_allocation() {
+    with_frames(|frames| {
+        let free = frames.zone_free_frames(Zone::Dma);
+        let frame = frames.allocate_block_in(Zone::Dma, 0).expect("no frame below 16 MiB");
+        assert!(frame.start_address().as_u64() < 16 * 1024 * 1024);
+        assert_eq!(frames.zone_free_frames(Zone::Dma), free - 1);
+        unsafe { frames.deallocate_block(frame, 0) };
+        assert_eq!(frames.zone_free_frames(Zone::Dma), free);
+    });
+}
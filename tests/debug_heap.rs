@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc};
+use bootloader::{entry_point, BootInfo};
+use core::{alloc::Layout, panic::PanicInfo};
+use enigma::testing::TestCase;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+#[test_case]
+const BUFFER_OVERFLOW: TestCase =
+    TestCase::new("debug_heap::buffer_overflow", buffer_overflow).expect_panic_with("buffer overflow");
+
+fn buffer_overflow() {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!(!ptr.is_null());
+        // fresh memory is poisoned
+        assert!((0..layout.size()).all(|i| *ptr.add(i) == 0xCD));
+        // one byte past the end lands in the red zone
+        ptr.add(layout.size()).write_volatile(0);
+        dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+const DOUBLE_FREE: TestCase =
+    TestCase::new("debug_heap::double_free", double_free).expect_panic_with("double free");
+
+fn double_free() {
+    // Large enough to go past the block lists, so nothing reuses the block in between
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!(!ptr.is_null());
+        dealloc(ptr, layout);
+        // freed memory is poisoned
+        assert!((0..layout.size()).all(|i| *ptr.add(i) == 0xDD));
+        dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use enigma::task::{executor::Executor, Task};
+use enigma::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    serial_print!("executor::tasks_run_to_completion...\t");
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(yielding_task()));
+    }
+    executor.spawn(Task::new(check_completed()));
+    executor.run();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+// Each task returns `Pending` once before finishing, so the executor has to rely on the waker to poll
+// it a second time
+async fn yielding_task() {
+    YieldNow(false).await;
+    COMPLETED.fetch_add(1, Ordering::SeqCst);
+}
+
+async fn check_completed() {
+    while COMPLETED.load(Ordering::SeqCst) < 3 {
+        YieldNow(false).await;
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Future that returns `Pending` on its first poll and wakes itself immediately.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
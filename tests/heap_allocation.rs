@@ -16,13 +16,15 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use enigma::allocator;
-    use enigma::memory::{self, BootInfoFrameAllocator};
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
 
     enigma::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("haep initialization failed");
+    memory::install(mapper, frame_allocator); // Lets the heap grow on demand
+    enigma::serial_println!("Heap allocator: {}", allocator::ALLOCATOR_NAME);
 
     test_main();
     loop {}
@@ -64,7 +66,8 @@ fn many_boxes() {
     }
 }
 
-// this test failes for  Bump Allocator
+// The bump allocator can't reuse memory while the long lived box exists, it only passes because the
+// heap grows
 #[test_case]
 fn many_boxes_long_lived() {
     let long_lived = Box::new(1);
@@ -75,3 +78,13 @@ fn many_boxes_long_lived() {
 
     assert_eq!(*long_lived, 1);
 }
+
+// Allocates more than the initially mapped heap, which only works if the heap grows on demand
+#[test_case]
+fn allocation_larger_than_initial_heap() {
+    let size = HEAP_SIZE * 4;
+    let mut vec: Vec<u8> = Vec::with_capacity(size);
+    vec.resize(size, 0xab);
+    assert!(vec.iter().all(|&byte| byte == 0xab));
+    assert!(enigma::allocator::heap_mapped() > HEAP_SIZE);
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use enigma::allocator::{heap_stats, leak};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+#[test_case]
+fn counts_allocations() {
+    let before = heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = heap_stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+    drop(value);
+    let after = heap_stats();
+    assert_eq!(after.deallocations, before.deallocations + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.live_allocations(), before.live_allocations());
+}
+
+#[test_case]
+fn tracks_peak_usage() {
+    let vec: Vec<u8> = Vec::with_capacity(16 * 1024);
+    let in_use = heap_stats().bytes_in_use;
+    drop(vec);
+    let stats = heap_stats();
+    assert!(stats.peak_bytes_in_use >= in_use);
+    assert!(stats.bytes_in_use < stats.peak_bytes_in_use);
+    assert!(stats.fragmentation_percent() <= 100);
+    assert!(stats.largest_free_block <= stats.free_bytes);
+}
+
+// Only the fixed-size block allocator has size classes
+#[test_case]
+fn counts_size_classes() {
+    if heap_stats().blocks.is_none() {
+        return;
+    }
+    let before = heap_stats().blocks.unwrap();
+    let small = Box::new(1u64);
+    let large = Box::new([0u8; 4096]);
+    let after = heap_stats().blocks.unwrap();
+    assert_eq!(after.allocations[0], before.allocations[0] + 1); // 8 byte blocks
+    assert_eq!(after.fallback_allocations, before.fallback_allocations + 1);
+    assert_eq!(after.fallback_bytes_in_use, before.fallback_bytes_in_use + 4096);
+    drop((small, large));
+}
+
+#[test_case]
+fn leak_tracking_finds_leaks() {
+    leak::start();
+    let freed = Box::new(1u32);
+    drop(freed);
+    assert_eq!(leak::checkpoint(), 0);
+
+    let leaked: &'static mut u32 = Box::leak(Box::new(2u32));
+    assert_eq!(leak::checkpoint(), 1);
+    leak::for_each_record(|record| {
+        assert_eq!(record.ptr, leaked as *mut u32 as usize);
+        assert_eq!(record.size, 4);
+        assert_ne!(record.callers[0], 0);
+    });
+    leak::stop();
+    enigma::allocator::dump_heap();
+}
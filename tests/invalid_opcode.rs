@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use enigma::testing::TestCase;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    enigma::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+// The invalid opcode handler reports the exception instead of escalating to a double fault
+#[test_case]
+const REPORTED_INSTEAD_OF_DOUBLE_FAULT: TestCase = TestCase::new(
+    "invalid_opcode::reported_instead_of_double_fault",
+    reported_instead_of_double_fault,
+)
+.expect_panic_with("[EXCEPTION] INVALID OPCODE");
+
+fn reported_instead_of_double_fault() {
+    unsafe { core::arch::asm!("ud2") };
+}
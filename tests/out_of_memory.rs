@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    alloc::Layout,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use enigma::allocator::{
+    heap_stats,
+    oom::{self, OomAction},
+    HEAP_MAX_SIZE,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+/// More than the heap window can ever hold, so growing the heap can't help.
+const TOO_LARGE: usize = 2 * HEAP_MAX_SIZE;
+
+static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn fail(_layout: &Layout, _attempt: usize) -> OomAction {
+    HOOK_CALLS.fetch_add(1, Ordering::SeqCst);
+    OomAction::Fail
+}
+
+fn retry_once(_layout: &Layout, attempt: usize) -> OomAction {
+    HOOK_CALLS.fetch_add(1, Ordering::SeqCst);
+    if attempt == 1 {
+        OomAction::Retry
+    } else {
+        OomAction::Fail
+    }
+}
+
+// The default hook must not shut down, fallible allocations report the error to the caller
+#[test_case]
+fn try_reserve_fails_with_default_hook() {
+    let before = heap_stats();
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(TOO_LARGE).is_err());
+    assert_eq!(heap_stats().failed_allocations, before.failed_allocations + 1);
+}
+
+#[test_case]
+fn hook_is_called_on_failure() {
+    HOOK_CALLS.store(0, Ordering::SeqCst);
+    let previous = oom::set_hook(fail);
+    let before = heap_stats();
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(TOO_LARGE).is_err());
+
+    oom::set_hook(previous);
+    assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(heap_stats().failed_allocations, before.failed_allocations + 1);
+}
+
+#[test_case]
+fn retry_allocates_again() {
+    HOOK_CALLS.store(0, Ordering::SeqCst);
+    let previous = oom::set_hook(retry_once);
+    let before = heap_stats();
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(TOO_LARGE).is_err());
+
+    oom::set_hook(previous);
+    assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(heap_stats().failed_allocations, before.failed_allocations + 2);
+}
+
+#[test_case]
+fn heap_still_works_after_failure() {
+    let previous = oom::set_hook(fail);
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(TOO_LARGE).is_err());
+    oom::set_hook(previous);
+
+    let vec: Vec<u64> = (0..1000).collect();
+    assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+}
@@ -1,26 +1,44 @@
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use enigma::{QemuExitCode, exit_qemu, serial_println,serial_print};
+use enigma::testing::TestCase;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
+    test_main();
 
     loop {}
 }
 
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
 }
 
+#[test_case]
+const SHOULD_FAIL: TestCase = TestCase::new("should_panic::should_fail", should_fail).expect_panic();
+
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
-    assert_eq!(0,1);
+    assert_eq!(0, 1);
+}
+
+#[test_case]
+const SHOULD_FAIL_WITH_MESSAGE: TestCase =
+    TestCase::new("should_panic::should_fail_with_message", should_fail_with_message)
+        .expect_panic_with("index out of bounds");
+
+fn should_fail_with_message() {
+    let values = [1, 2, 3];
+    let index = values.len();
+    let _ = values[core::hint::black_box(index)];
+}
+
+#[test_case]
+fn runs_after_panics() {
+    // both tests above panicked, the runner still gets here
+    assert_eq!(1, 1);
 }
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use enigma::allocator::slab::SlabCache;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator); // Slabs are allocated from the kernel frame allocator
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+// 40 bytes, which the fixed-size block allocator would round up to 64
+struct Object {
+    magic: u64,
+    data: [u64; 4],
+}
+
+const MAGIC: u64 = 0xdead_beef;
+
+fn construct() -> Object {
+    Object {
+        magic: MAGIC,
+        data: [0; 4],
+    }
+}
+
+fn used_frames() -> usize {
+    enigma::memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+#[test_case]
+fn objects_are_constructed() {
+    static CACHE: SlabCache<Object> = SlabCache::new("constructed", construct);
+    let object = CACHE.alloc().unwrap();
+    assert_eq!(object.magic, MAGIC);
+    assert_eq!(CACHE.stats().object_size, 40);
+}
+
+// A freed object is handed out again without running the constructor
+#[test_case]
+fn objects_are_reused() {
+    static CACHE: SlabCache<Object> = SlabCache::new("reused", construct);
+    let mut object = CACHE.alloc().unwrap();
+    object.data[0] = 7;
+    let addr = &*object as *const Object;
+    drop(object);
+    let object = CACHE.alloc().unwrap();
+    assert_eq!(&*object as *const Object, addr);
+    assert_eq!(object.data[0], 7);
+}
+
+#[test_case]
+fn grows_by_slabs() {
+    static CACHE: SlabCache<Object> = SlabCache::new("grows", construct);
+    let per_slab = CACHE.stats().objects_per_slab;
+    let objects: Vec<_> = (0..per_slab + 1).map(|_| CACHE.alloc().unwrap()).collect();
+    let stats = CACHE.stats();
+    assert_eq!(stats.slabs, 2);
+    assert_eq!(stats.active_objects, per_slab + 1);
+    assert_eq!(stats.allocations, per_slab as u64 + 1);
+    assert!(objects.iter().all(|object| object.magic == MAGIC));
+    drop(objects);
+    let stats = CACHE.stats();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.empty_slabs, 2);
+}
+
+// Empty slabs go back to the frame allocator
+#[test_case]
+fn reclaim_returns_pages() {
+    static CACHE: SlabCache<Object> = SlabCache::new("reclaimed", construct);
+    // allocated first, so that growing the heap doesn't change the frame count
+    let mut objects = Vec::with_capacity(100);
+    let before = used_frames();
+    objects.extend((0..100).map(|_| CACHE.alloc().unwrap()));
+    assert!(used_frames() > before);
+    drop(objects);
+    let slabs = CACHE.stats().slabs;
+    assert_eq!(CACHE.reclaim(), slabs);
+    assert_eq!(used_frames(), before);
+    assert_eq!(CACHE.stats().slabs, 0);
+    assert_eq!(CACHE.stats().reclaimed_slabs, slabs as u64);
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(enigma::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use enigma::thread;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use enigma::allocator;
+    use enigma::memory::{self, buddy::BuddyFrameAllocator};
+
+    enigma::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    enigma::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_and_join() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| RAN.store(true, Ordering::SeqCst));
+    handle.join();
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+// The test thread never yields while it waits, so the spawned thread only runs if the timer preempts
+#[test_case]
+fn timer_preempts_busy_thread() {
+    static FLAG: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| FLAG.store(true, Ordering::SeqCst));
+    while !FLAG.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    handle.join();
+}
+
+#[test_case]
+fn yield_now_round_robin() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let handles = [
+        thread::spawn(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }),
+        thread::spawn(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }),
+    ];
+    while COUNTER.load(Ordering::SeqCst) < 2 {
+        thread::yield_now();
+    }
+    for handle in handles {
+        handle.join();
+    }
+}
+
+#[test_case]
+fn exit_skips_rest_of_thread() {
+    static AFTER_EXIT: AtomicBool = AtomicBool::new(false);
+    thread::spawn(|| {
+        thread::exit();
+        #[allow(unreachable_code)]
+        AFTER_EXIT.store(true, Ordering::SeqCst);
+    })
+    .join();
+    assert!(!AFTER_EXIT.load(Ordering::SeqCst));
+}